@@ -0,0 +1,109 @@
+//! Counts heap allocations made while parsing a wide element (many
+//! attributes) and a broad one (many children).
+//!
+//! The pest parse is measured on its own as well, so the reallocations
+//! made while building the tree can be told apart. With the attribute and
+//! child vectors pre-sized, that number stays flat however wide the element
+//! gets; the run fails if it grows.
+//!
+//! Run with `cargo bench --bench allocations`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use pest::Parser;
+use xml_parser::{Grammar, Rule, parse_xml};
+
+struct CountingAllocator;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static REALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        REALLOCS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const ROUNDS: u32 = 20;
+
+struct Counts {
+    allocs: usize,
+    reallocs: usize,
+}
+
+fn count(mut run: impl FnMut()) -> Counts {
+    // warm up so one-time allocations are not counted
+    run();
+
+    ALLOCS.store(0, Ordering::Relaxed);
+    REALLOCS.store(0, Ordering::Relaxed);
+    for _ in 0..ROUNDS {
+        run();
+    }
+    Counts {
+        allocs: ALLOCS.load(Ordering::Relaxed) / ROUNDS as usize,
+        reallocs: REALLOCS.load(Ordering::Relaxed) / ROUNDS as usize,
+    }
+}
+
+// reallocations made while building the tree, on top of the pest parse
+fn measure(label: &str, input: &str) -> usize {
+    let start = Instant::now();
+    let total = count(|| {
+        parse_xml(input).expect("benchmark input is valid XML");
+    });
+    let elapsed = start.elapsed() / (ROUNDS + 1);
+    let pest = count(|| {
+        Grammar::parse(Rule::xml, input).expect("benchmark input is valid XML");
+    });
+
+    let tree_reallocs = total.reallocs - pest.reallocs;
+    println!(
+        "{label:<24} {:>8} allocs {:>6} reallocs ({tree_reallocs} building the tree) {elapsed:>12.2?} per parse",
+        total.allocs, total.reallocs,
+    );
+    tree_reallocs
+}
+
+fn assert_flat(label: &str, reallocs: &[usize]) {
+    assert!(
+        reallocs.windows(2).all(|pair| pair[1] <= pair[0]),
+        "{label}: reallocations building the tree grow with the element: {reallocs:?}"
+    );
+}
+
+fn main() {
+    let wide: Vec<usize> = [10, 100, 1000]
+        .into_iter()
+        .map(|n| {
+            let attributes = (0..n).map(|i| format!(" a{i}=\"{i}\"")).collect::<String>();
+            measure(&format!("wide, {n} attributes"), &format!("<root{attributes}/>"))
+        })
+        .collect();
+
+    let broad: Vec<usize> = [10, 100, 1000]
+        .into_iter()
+        .map(|n| {
+            let children = "<item/>".repeat(n);
+            measure(&format!("broad, {n} children"), &format!("<root>{children}</root>"))
+        })
+        .collect();
+
+    assert_flat("wide", &wide);
+    assert_flat("broad", &broad);
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Any input must produce either a tree or a ParseError; a panic or hang is a bug.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(input) = std::str::from_utf8(data) {
+        let _ = xml_parser::parse_xml(input);
+    }
+
+    let _ = xml_parser::parse_stream(data).count();
+});
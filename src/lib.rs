@@ -1,391 +1,5065 @@
-use pest::Parser;
+use pest::Parser as _;
 use pest_derive::Parser;
 use thiserror::Error;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{fs, io};
 
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
 pub struct Grammar;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "json", serde(deny_unknown_fields))]
 pub struct XmlNode {
     pub name: String,
+    /// Text of the element. Always valid UTF-8; binary payloads have to be
+    /// embedded encoded, see `decode_base64_content`.
+    #[cfg_attr(feature = "json", serde(default))]
     pub content: String,
+    /// Attributes exactly in source order; parsing and serialization
+    /// preserve this order. Use `attributes_sorted` for a by-name view.
+    #[cfg_attr(feature = "json", serde(default))]
     pub attributes: Vec<(String, String)>,
+    #[cfg_attr(feature = "json", serde(default))]
     pub children: Vec<XmlNode>,
+    // `xml:lang` in effect on the parent when this node was parsed
+    #[cfg_attr(feature = "json", serde(skip))]
+    inherited_lang: Option<String>,
 }
 
 impl std::fmt::Display for XmlNode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.display_node(f, 0)
+        self.display_node(f, 0, &DisplayOptions::default())
+    }
+}
+
+impl std::str::FromStr for XmlNode {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        parse_xml(input)
+    }
+}
+
+impl TryFrom<&str> for XmlNode {
+    type Error = ParseError;
+
+    fn try_from(input: &str) -> Result<Self, Self::Error> {
+        parse_xml(input)
+    }
+}
+
+impl TryFrom<String> for XmlNode {
+    type Error = ParseError;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        parse_xml(&input)
+    }
+}
+
+struct TreeView<'a> {
+    node: &'a XmlNode,
+    opts: &'a DisplayOptions,
+}
+
+impl std::fmt::Display for TreeView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.node.display_node(f, 0, self.opts)
+    }
+}
+
+/// Settings for the `Display` tree view; the default matches `Display`.
+#[derive(Debug, Clone, Default)]
+pub struct DisplayOptions {
+    /// Print only this many levels below the node, see `display_preview`.
+    pub max_depth: Option<usize>,
+    /// Wrap tag names, attributes and comments in ANSI color codes.
+    pub color: bool,
+}
+
+const TAG_COLOR: &str = "\x1b[34m";
+const ATTRIBUTE_COLOR: &str = "\x1b[36m";
+const VALUE_COLOR: &str = "\x1b[32m";
+const MARKUP_COLOR: &str = "\x1b[90m";
+const RESET_COLOR: &str = "\x1b[0m";
+
+// `text` wrapped in `code`, or unchanged when color is off
+fn paint(text: &str, code: &str, color: bool) -> String {
+    if color {
+        format!("{}{}{}", code, text, RESET_COLOR)
+    } else {
+        text.to_string()
     }
 }
 
 impl XmlNode {
-    pub fn from_path(path: &str) -> Result<Self, ParseError> {
+    /// Creates an empty element. The name is not validated, so an invalid
+    /// name such as `"1bad"` produces non-well-formed output when serialized;
+    /// use `try_element` for untrusted names.
+    pub fn element(name: impl Into<String>) -> Self {
+        XmlNode {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn try_element(name: impl Into<String>) -> Result<Self, ParseError> {
+        let name = name.into();
+        if !is_valid_name(&name) {
+            return Err(ParseError::InvalidName { name });
+        }
+        Ok(Self::element(name))
+    }
+
+    pub fn with_content(mut self, content: impl Into<String>) -> Self {
+        self.content = content.into();
+        self
+    }
+
+    pub fn with_attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn with_child(mut self, child: XmlNode) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    /// A `#text` node holding literal text, for mixing text and elements in
+    /// a children list. It is escaped when serialized. The parser never
+    /// creates `#text` nodes; parsed text is kept in `content`.
+    pub fn text(content: impl Into<String>) -> Self {
+        XmlNode {
+            name: "#text".to_string(),
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    /// A `#cdata` node for the literal text `content`, like those parsed
+    /// from `<![CDATA[...]]>`. A `]]>` inside is split across two sections.
+    pub fn cdata(content: &str) -> Self {
+        XmlNode {
+            name: "#cdata".to_string(),
+            content: wrap_cdata(content),
+            ..Default::default()
+        }
+    }
+
+    /// Moves the children out of the node, e.g. to reparent them without
+    /// cloning.
+    pub fn into_children(self) -> Vec<XmlNode> {
+        self.children
+    }
+
+    /// Moves the node apart into its name, attributes, content and children.
+    pub fn into_parts(self) -> (String, Vec<(String, String)>, String, Vec<XmlNode>) {
+        (self.name, self.attributes, self.content, self.children)
+    }
+
+    /// Reads and parses the file at `path`. Parse errors are wrapped in
+    /// `ParseError::InFile` naming the file; I/O errors are returned as is.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, ParseError> {
+        let path = path.as_ref();
         let data = fs::read_to_string(path)?;
-        parse_xml(&data)
+        parse_xml(&data).map_err(|err| in_file(path, err))
+    }
+
+    /// Like `from_path`, but memory-maps the file and parses it in place
+    /// instead of reading it into a `String` first, so large files are not
+    /// held in memory twice (`mmap` feature).
+    ///
+    /// The mapping reflects the file while it is parsed: if another process
+    /// modifies or truncates the file meanwhile, the parse may see
+    /// inconsistent text, and a truncation can crash the process with
+    /// `SIGBUS`. Only use it on files nothing else writes to during the call.
+    #[cfg(feature = "mmap")]
+    pub fn from_path_mmap(path: impl AsRef<Path>) -> Result<Self, ParseError> {
+        let path = path.as_ref();
+        let file = fs::File::open(path)?;
+        // SAFETY: the map is only read during this call; the caveat about
+        // concurrent writers is documented above
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let data = std::str::from_utf8(&map)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        parse_xml(data).map_err(|err| in_file(path, err))
+    }
+
+    /// Writes every direct child element named `tag` to `dir/<tag>_<N>.xml`
+    /// (N counted from 1), creating `dir` if needed. Returns the number of
+    /// files written.
+    pub fn write_children_named<P: AsRef<Path>>(&self, tag: &str, dir: P) -> io::Result<usize> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let mut written = 0;
+        for child in self.children.iter().filter(|child| child.name == tag) {
+            written += 1;
+            let file = dir.join(format!("{}_{}.xml", tag, written));
+            fs::write(file, child.to_xml_string())?;
+        }
+        Ok(written)
+    }
+
+    /// Serializes the tree to JSON. Every node is an object with a `name`
+    /// string, a `content` string, `attributes` as an array of `[key, value]`
+    /// pairs and `children` as an array of nodes.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        // the tree contains only strings and vectors, which always serialize
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Reconstructs a tree from the JSON shape produced by `to_json`.
+    /// `name` is required; `content`, `attributes` and `children` may be
+    /// omitted. Unknown fields or mismatched types yield `InvalidJson`.
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &str) -> Result<XmlNode, ParseError> {
+        serde_json::from_str(json).map_err(|e| ParseError::InvalidJson { message: e.to_string() })
+    }
+
+    /// Content of the first `tag` element with non-empty content, in
+    /// document order. The search stops at that match: no node after it is
+    /// visited. Comment nodes never match; see `get_contents_of_with` to
+    /// include them.
+    pub fn get_contents_of(&self, tag: &str) -> Option<&str> {
+        self.get_contents_of_with(tag, &TextOptions::default())
+    }
+
+    /// Like `get_contents_of`; with `include_comments`, a `#comment` tag
+    /// matches comments and yields their text without `<!--`/`-->`.
+    pub fn get_contents_of_with(&self, tag: &str, opts: &TextOptions) -> Option<&str> {
+        #[cfg(test)]
+        count_visit();
+        let text = match self.name.as_str() {
+            "#comment" if opts.include_comments => self.comment_text(),
+            _ if self.is_comment_or_text() => "",
+            _ => &self.content,
+        };
+        if self.name == tag && !text.is_empty() {
+            return Some(text);
+        }
+
+        for child in &self.children {
+            if let Some(found) = child.get_contents_of_with(tag, opts) {
+                return Some(found);
+            }
+        }
+
+        None
+    }
+
+    /// Value of the first `tag` element (in document order) that has one: its
+    /// content if non-empty, otherwise its `attr` attribute. Content always
+    /// wins over the attribute on the same element.
+    pub fn value_of(&self, tag: &str, attr: Option<&str>) -> Option<&str> {
+        self.get_nodes(tag).into_iter().filter(|node| !node.is_comment_or_text()).find_map(|node| {
+            if !node.content.is_empty() {
+                Some(node.content.as_str())
+            } else {
+                attr.and_then(|attr| node.get_attribute(attr))
+            }
+        })
+    }
+
+    /// Like `get_contents_of`, but tells apart a missing tag from one that is
+    /// present with empty content.
+    pub fn try_get_contents_of(&self, tag: &str) -> Result<&str, ContentError> {
+        if let Some(content) = self.get_contents_of(tag) {
+            return Ok(content);
+        }
+
+        if self.get_nodes(tag).iter().all(|node| node.is_comment_or_text()) {
+            Err(ContentError::NotFound { tag: tag.to_string() })
+        } else {
+            Err(ContentError::Empty { tag: tag.to_string() })
+        }
+    }
+
+    /// All text of the subtree: this node's `content`, then the inner text
+    /// of each child, with CDATA sections unwrapped and comments skipped.
+    /// The tree does not keep where text sits between children, so a node's
+    /// own (merged) text always comes first: `<p>a<b>x</b>c</p>` gives
+    /// `"acx"`, not the document order `"axc"`. Note the difference from the
+    /// `content` field, which is only the node's own text: for
+    /// `<a>x<b>y</b></a>` `content` is `"x"` while `inner_text()` is `"xy"`.
+    pub fn inner_text(&self) -> String {
+        self.inner_text_with(&TextOptions::default())
+    }
+
+    /// Like `inner_text`; with `include_comments`, comment text (without
+    /// `<!--`/`-->`) is part of the result.
+    pub fn inner_text_with(&self, opts: &TextOptions) -> String {
+        let mut text = String::new();
+        self.collect_inner_text(&mut text, opts);
+        text
+    }
+
+    fn collect_inner_text(&self, text: &mut String, opts: &TextOptions) {
+        match self.name.as_str() {
+            "#comment" if opts.include_comments => text.push_str(self.comment_text()),
+            "#comment" | "#pi" => {}
+            "#cdata" => text.push_str(&self.cdata_text()),
+            _ => {
+                text.push_str(&self.content);
+                for child in &self.children {
+                    child.collect_inner_text(text, opts);
+                }
+            }
+        }
+    }
+
+    // the text of a `#comment` node without the `<!--`/`-->` delimiters
+    fn comment_text(&self) -> &str {
+        self.content
+            .strip_prefix("<!--")
+            .and_then(|c| c.strip_suffix("-->"))
+            .unwrap_or(&self.content)
+    }
+
+    // the text of a `#cdata` node without the `<![CDATA[`/`]]>` delimiters
+    fn cdata_text(&self) -> Cow<'_, str> {
+        let text = self
+            .content
+            .strip_prefix("<![CDATA[")
+            .and_then(|c| c.strip_suffix("]]>"))
+            .unwrap_or(&self.content);
+        // `XmlNode::cdata` splits a `]]>` across adjacent sections
+        if text.contains("]]><![CDATA[") {
+            Cow::Owned(text.replace("]]><![CDATA[", ""))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// The `inner_text` of every node named `tag` in the subtree, in the
+    /// order of `get_nodes`, so text inside child elements is included
+    /// (after the node's own text, see `inner_text`).
+    pub fn collect_text(&self, tag: &str) -> Vec<String> {
+        self.get_nodes(tag).iter().map(|node| node.inner_text()).collect()
+    }
+
+    /// This node's own text; unlike `get_contents_of`, never looks at descendants.
+    pub fn own_content(&self) -> &str {
+        &self.content
+    }
+
+    /// The content without leading and trailing whitespace.
+    pub fn content_trimmed(&self) -> &str {
+        self.content.trim()
+    }
+
+    /// Whether the content is empty or only whitespace. Content is trimmed
+    /// unless parsed with `preserve_whitespace`, so without it this is true
+    /// only for empty content.
+    pub fn content_is_whitespace(&self) -> bool {
+        self.content.trim().is_empty()
+    }
+
+    /// Decodes the content as standard base64 (`+`, `/`, `=` padding).
+    /// Whitespace such as line breaks inside the payload is ignored.
+    pub fn decode_base64_content(&self) -> Result<Vec<u8>, Base64Error> {
+        decode_base64(&self.content)
+    }
+
+    /// Every node named `tag` in the subtree, including this node, in
+    /// document order (pre-order: a parent before its children, siblings
+    /// left to right). The tag `*` matches every element; pseudo-nodes are
+    /// never returned for it.
+    /// A tag in Clark notation, `{uri}local`, is matched by namespace as in
+    /// `get_nodes_ns`.
+    pub fn get_nodes(&self, tag: &str) -> Vec<&XmlNode> {
+        if let Some((namespace_uri, local)) = split_clark_name(tag) {
+            return self.get_nodes_ns(namespace_uri, local);
+        }
+
+        let mut results = Vec::new();
+
+        if self.is_named(tag) {
+            results.push(self);
+        }
+
+        for child in &self.children {
+            results.extend(child.get_nodes(tag));
+        }
+        results
+    }
+
+    /// The matches of `get_nodes` in reverse document order, e.g. for
+    /// "last occurrence wins" lookups.
+    pub fn get_nodes_rev(&self, tag: &str) -> Vec<&XmlNode> {
+        let mut nodes = self.get_nodes(tag);
+        nodes.reverse();
+        nodes
+    }
+
+    /// Like `get_nodes`, but compares names ignoring ASCII case, so `item`
+    /// also finds `<Item>`. Names in the tree keep their original case.
+    pub fn get_nodes_ci(&self, tag: &str) -> Vec<&XmlNode> {
+        let mut results = Vec::new();
+        self.collect_nodes_ci(tag, &mut results);
+        results
+    }
+
+    fn collect_nodes_ci<'a>(&'a self, tag: &str, results: &mut Vec<&'a XmlNode>) {
+        if self.name.eq_ignore_ascii_case(tag) || self.is_named(tag) {
+            results.push(self);
+        }
+        for child in &self.children {
+            child.collect_nodes_ci(tag, results);
+        }
+    }
+
+    /// Like `get_nodes`, but pairs every match with its 1-based position
+    /// among the direct siblings sharing its name, as in `item[2]`. This
+    /// node itself counts as position 1. Clark notation is accepted.
+    pub fn get_nodes_indexed(&self, tag: &str) -> Vec<(usize, &XmlNode)> {
+        let mut results = Vec::new();
+        self.collect_nodes_indexed(&*self.tag_matcher(tag), 1, &mut results);
+        results
+    }
+
+    fn collect_nodes_indexed<'a>(
+        &'a self,
+        matches: &dyn Fn(&XmlNode) -> bool,
+        index: usize,
+        results: &mut Vec<(usize, &'a XmlNode)>,
+    ) {
+        if matches(self) {
+            results.push((index, self));
+        }
+
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for child in &self.children {
+            let index = seen.entry(child.name.as_str()).or_default();
+            *index += 1;
+            child.collect_nodes_indexed(matches, *index, results);
+        }
+    }
+
+    /// The shallowest node named `tag` (this node included), found
+    /// breadth-first; among matches at the same depth the first in document
+    /// order wins. Unlike `get_nodes(tag).first()`, a match deep inside an
+    /// early child loses to a shallower one in a later child. Clark
+    /// notation is accepted.
+    pub fn nearest(&self, tag: &str) -> Option<&XmlNode> {
+        let matches = self.tag_matcher(tag);
+        let mut queue = VecDeque::from([self]);
+        while let Some(node) = queue.pop_front() {
+            if matches(node) {
+                return Some(node);
+            }
+            queue.extend(&node.children);
+        }
+        None
+    }
+
+    /// Whether this node's own content contains `needle` (case-sensitive).
+    pub fn contains_text(&self, needle: &str) -> bool {
+        self.content.contains(needle)
+    }
+
+    /// Like `contains_text`, ignoring ASCII case like every `_ci` method:
+    /// `"HELLO"` finds `"hello"`, but `"É"` does not find `"é"`.
+    pub fn contains_text_ci(&self, needle: &str) -> bool {
+        contains_ignore_ascii_case(&self.content, needle)
+    }
+
+    /// Every element of the subtree, including this node, whose own content
+    /// contains `needle` (case-sensitive), in document order.
+    pub fn find_text(&self, needle: &str) -> Vec<&XmlNode> {
+        self.get_nodes("*").into_iter().filter(|node| node.contains_text(needle)).collect()
+    }
+
+    /// Like `find_text`, ignoring ASCII case (see `contains_text_ci`).
+    pub fn find_text_ci(&self, needle: &str) -> Vec<&XmlNode> {
+        self.get_nodes("*").into_iter().filter(|node| node.contains_text_ci(needle)).collect()
+    }
+
+    // `*` stands for any element name
+    fn is_named(&self, tag: &str) -> bool {
+        self.name == tag || (tag == "*" && self.is_element())
+    }
+
+    // `is_named` for lookups that walk the tree in their own order; a Clark
+    // name needs namespace scope, so its matches are resolved up front
+    fn tag_matcher<'a>(&'a self, tag: &'a str) -> Box<dyn Fn(&XmlNode) -> bool + 'a> {
+        match split_clark_name(tag) {
+            Some((namespace_uri, local)) => {
+                let found: HashSet<*const XmlNode> = self
+                    .get_nodes_ns(namespace_uri, local)
+                    .into_iter()
+                    .map(|node| node as *const XmlNode)
+                    .collect();
+                Box::new(move |node| found.contains(&(node as *const XmlNode)))
+            }
+            None => Box::new(move |node| node.is_named(tag)),
+        }
+    }
+
+    /// Every element of the subtree, including this node, in document
+    /// order. Comment, CDATA and processing-instruction nodes are excluded.
+    pub fn all_elements(&self) -> Vec<&XmlNode> {
+        self.get_nodes("*")
+    }
+
+    /// Like `get_nodes`, but stops walking the tree once `max` matches are found.
+    /// Clark notation is accepted.
+    pub fn get_nodes_limited(&self, tag: &str, max: usize) -> Vec<&XmlNode> {
+        let mut results = Vec::new();
+        match split_clark_name(tag) {
+            Some((namespace_uri, local)) => {
+                self.collect_nodes_ns(namespace_uri, local, max, &mut Vec::new(), &mut results)
+            }
+            None => self.collect_nodes_limited(tag, max, &mut results),
+        }
+        results
+    }
+
+    fn collect_nodes_limited<'a>(&'a self, tag: &str, max: usize, results: &mut Vec<&'a XmlNode>) {
+        if results.len() >= max {
+            return;
+        }
+        #[cfg(test)]
+        count_visit();
+        if self.is_named(tag) {
+            results.push(self);
+        }
+        for child in &self.children {
+            child.collect_nodes_limited(tag, max, results);
+        }
+    }
+
+    /// Every node below this one (not including it) in document order,
+    /// pseudo-nodes included.
+    pub fn descendants(&self) -> impl Iterator<Item = &XmlNode> {
+        Descendants { stack: self.children.iter().rev().collect() }
+    }
+
+    /// Nodes in the subtree, this one and pseudo-nodes included.
+    pub fn node_count(&self) -> usize {
+        1 + self.children.iter().map(XmlNode::node_count).sum::<usize>()
+    }
+
+    /// Rough heap footprint of the subtree in bytes: the capacities of all
+    /// names, contents and attribute strings. Vector and node overhead is
+    /// not counted, so treat it as a lower bound.
+    pub fn estimated_size(&self) -> usize {
+        let attributes: usize =
+            self.attributes.iter().map(|(name, value)| name.capacity() + value.capacity()).sum();
+        let children: usize = self.children.iter().map(XmlNode::estimated_size).sum();
+        self.name.capacity() + self.content.capacity() + attributes + children
+    }
+
+    /// Tests this node alone against a simple selector: a tag name or `*`,
+    /// optionally followed by predicates `[@attr]` (present), `[@attr='v']`
+    /// (equal) and their negations `[not(@attr)]`, `[not(@attr='v')]`.
+    /// Pseudo-nodes and malformed selectors never match.
+    pub fn matches(&self, selector: &str) -> bool {
+        parse_selector(selector).is_some_and(|selector| selector.matches(self))
+    }
+
+    /// Every element of the subtree, including this node, that `matches`
+    /// the selector, in document order. A leading `//` (as in
+    /// `//item[@disabled]`) is accepted and changes nothing.
+    pub fn select(&self, selector: &str) -> Vec<&XmlNode> {
+        let selector = selector.trim();
+        let Some(selector) = parse_selector(selector.strip_prefix("//").unwrap_or(selector)) else {
+            return Vec::new();
+        };
+        self.get_nodes("*").into_iter().filter(|node| selector.matches(node)).collect()
+    }
+
+    /// The direct child at 0-based `index`, counting comment, CDATA and
+    /// processing-instruction nodes.
+    pub fn nth_child(&self, index: usize) -> Option<&XmlNode> {
+        self.children.get(index)
+    }
+
+    /// The direct child element at 0-based `index`, skipping pseudo-nodes.
+    pub fn nth_element_child(&self, index: usize) -> Option<&XmlNode> {
+        self.children.iter().filter(|child| child.is_element()).nth(index)
+    }
+
+    pub fn children_mut(&mut self) -> &mut Vec<XmlNode> {
+        &mut self.children
+    }
+
+    pub fn child_mut(&mut self, index: usize) -> Option<&mut XmlNode> {
+        self.children.get_mut(index)
+    }
+
+    /// Calls `f` on this node and then on every descendant, depth-first.
+    /// Pseudo-nodes (`#comment`, `#cdata`, `#pi`) are visited too.
+    pub fn for_each_mut(&mut self, f: &mut impl FnMut(&mut XmlNode)) {
+        f(self);
+        for child in &mut self.children {
+            child.for_each_mut(f);
+        }
+    }
+
+    /// Removes every attribute named `key` from this node and all
+    /// descendants. Returns the number of attributes removed.
+    pub fn remove_attribute_everywhere(&mut self, key: &str) -> usize {
+        let mut removed = 0;
+        self.for_each_mut(&mut |node| {
+            let before = node.attributes.len();
+            node.attributes.retain(|(k, _)| k != key);
+            removed += before - node.attributes.len();
+        });
+        removed
+    }
+
+    /// Keeps only the descendants for which `pred` returns true, like
+    /// `Vec::retain` applied at every level. The predicate runs top-down: it
+    /// sees each child before that child's own children are filtered, and a
+    /// removed child's subtree is dropped without being visited. This node
+    /// itself is always kept.
+    pub fn retain_children(&mut self, mut pred: impl FnMut(&XmlNode) -> bool) {
+        self.retain_children_with(&mut pred);
+    }
+
+    fn retain_children_with(&mut self, pred: &mut impl FnMut(&XmlNode) -> bool) {
+        self.children.retain(|child| pred(child));
+        for child in &mut self.children {
+            child.retain_children_with(pred);
+        }
+    }
+
+    /// Empties the content of every element in the subtree whose content is
+    /// only whitespace, such as the indentation kept by `preserve_whitespace`
+    /// around child elements. Other text is left untouched.
+    pub fn prune_whitespace_only_text(&mut self) {
+        self.for_each_mut(&mut |node| {
+            if node.is_element() && node.content_is_whitespace() {
+                node.content.clear();
+            }
+        });
+    }
+
+    /// Removes, bottom-up, every descendant element without content,
+    /// attributes or children, so elements left empty by the removal go too.
+    /// Comment, CDATA and processing-instruction nodes count as children and
+    /// keep their parent; they are never removed. This node itself is kept.
+    /// Returns the number of elements removed.
+    pub fn prune_empty(&mut self) -> usize {
+        let mut removed = 0;
+        for child in &mut self.children {
+            removed += child.prune_empty();
+        }
+
+        let before = self.children.len();
+        self.children.retain(|child| {
+            !(child.is_element() && child.is_empty_element() && child.attributes.is_empty())
+        });
+        removed + before - self.children.len()
+    }
+
+    /// Sets the literal text of the node. Do not escape it: `content` always
+    /// holds decoded text and serialization escapes it.
+    pub fn set_content(&mut self, content: impl Into<String>) {
+        self.content = content.into();
+    }
+
+    /// Replaces the value of the first attribute named `key`, or appends it.
+    pub fn set_attribute(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self.attributes.iter_mut().find(|(k, _)| k == key) {
+            Some((_, existing)) => *existing = value,
+            None => self.attributes.push((key.to_string(), value)),
+        }
+    }
+
+    /// Serializes the subtree back to XML without any added whitespace.
+    /// Content is escaped (`&`, `<` and the `>` of `]]>`), and so are
+    /// attribute values (`&`, `<`, `"` and whitespace other than spaces).
+    pub fn to_xml_string(&self) -> String {
+        self.to_xml_string_with(&SerializeOptions::default())
+    }
+
+    pub fn to_xml_string_with(&self, opts: &SerializeOptions) -> String {
+        let mut out = String::new();
+        if let Some(declaration) = &opts.emit_declaration {
+            out.push_str(&format!("{}\n", declaration));
+        }
+        self.write_xml(&mut out, opts);
+        out
+    }
+
+    fn write_xml(&self, out: &mut String, opts: &SerializeOptions) {
+        match self.name.as_str() {
+            "#comment" | "#cdata" | "#pi" => out.push_str(&self.content),
+            "#text" => out.push_str(&escape_text(&self.content)),
+            _ => {
+                out.push('<');
+                out.push_str(&self.name);
+                self.write_attributes(out, opts.sort_attributes);
+                if opts.collapse_empty && self.is_empty_element() {
+                    out.push_str("/>");
+                    return;
+                }
+                out.push('>');
+                match opts.cdata_threshold {
+                    Some(threshold) if markup_char_count(&self.content) >= threshold.max(1) => {
+                        out.push_str(&wrap_cdata(&self.content));
+                    }
+                    _ => out.push_str(&escape_text(&self.content)),
+                }
+
+                for child in &self.children {
+                    child.write_xml(out, opts);
+                }
+
+                out.push_str(&format!("</{}>", self.name));
+            }
+        }
+    }
+
+    /// Writes the compact `to_xml_string` form to `writer`.
+    pub fn to_writer<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        self.to_writer_with(writer, &SerializeOptions::default())
+    }
+
+    /// Writes `to_xml_string_with(opts)` to `writer`.
+    pub fn to_writer_with<W: io::Write>(&self, mut writer: W, opts: &SerializeOptions) -> io::Result<()> {
+        writer.write_all(self.to_xml_string_with(opts).as_bytes())
+    }
+
+    /// Writes the indented form (`to_xml_string_pretty` with default
+    /// options) to `writer`.
+    pub fn to_writer_pretty<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(self.to_xml_string_pretty(&PrettyOptions::default()).as_bytes())
+    }
+
+    /// Serializes the subtree as indented XML: every element, comment and
+    /// CDATA section starts on its own line. Elements without child nodes
+    /// keep their content on the same line.
+    pub fn to_xml_string_pretty(&self, opts: &PrettyOptions) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, opts, &opts.indent.unit(), 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, opts: &PrettyOptions, unit: &str, depth: usize) {
+        let pad = unit.repeat(depth);
+        if !self.is_element() || self.children.is_empty() {
+            let inline = SerializeOptions {
+                sort_attributes: opts.sort_attributes,
+                ..SerializeOptions::default()
+            };
+            out.push_str(&pad);
+            self.write_xml(out, &inline);
+            out.push('\n');
+            return;
+        }
+
+        out.push_str(&pad);
+        out.push('<');
+        out.push_str(&self.name);
+        self.write_attributes(out, opts.sort_attributes);
+        out.push_str(">\n");
+        if !self.content.is_empty() {
+            out.push_str(&format!("{}{}{}\n", pad, unit, escape_text(&self.content)));
+        }
+        for child in &self.children {
+            child.write_pretty(out, opts, unit, depth + 1);
+        }
+        out.push_str(&format!("{}</{}>\n", pad, self.name));
+    }
+
+    // ` k="v"` for every attribute; `sorted` orders them by name and drops
+    // repeated names after the first
+    fn write_attributes(&self, out: &mut String, sorted: bool) {
+        let mut attributes = self.attributes.iter().collect::<Vec<_>>();
+        if sorted {
+            attributes.sort_by(|(a, _), (b, _)| a.cmp(b));
+            attributes.dedup_by(|(a, _), (b, _)| a == b);
+        }
+        for (k, v) in attributes {
+            out.push_str(&format!(" {}=\"{}\"", k, escape_attribute(v)));
+        }
+    }
+
+    /// The part of the name after the namespace prefix, e.g. `entry` for
+    /// `atom:entry`.
+    pub fn local_name(&self) -> &str {
+        self.name.split_once(':').map_or(self.name.as_str(), |(_, local)| local)
+    }
+
+    pub fn prefix(&self) -> Option<&str> {
+        self.name.split_once(':').map(|(prefix, _)| prefix)
+    }
+
+    /// Elements whose resolved namespace URI and local name match, whatever
+    /// prefix they use. Declarations (`xmlns`, `xmlns:p`) are resolved within
+    /// this subtree only, so call it on the document root.
+    pub fn get_nodes_ns(&self, namespace_uri: &str, local: &str) -> Vec<&XmlNode> {
+        let mut results = Vec::new();
+        self.collect_nodes_ns(namespace_uri, local, usize::MAX, &mut Vec::new(), &mut results);
+        results
+    }
+
+    fn collect_nodes_ns<'a>(
+        &'a self,
+        namespace_uri: &str,
+        local: &str,
+        max: usize,
+        scope: &mut Vec<(&'a str, &'a str)>,
+        results: &mut Vec<&'a XmlNode>,
+    ) {
+        if !self.is_element() || results.len() >= max {
+            return;
+        }
+        #[cfg(test)]
+        count_visit();
+
+        let outer_len = scope.len();
+        scope.extend(namespace_declarations(&self.attributes));
+
+        if self.is_in_namespace(namespace_uri, local, scope) {
+            results.push(self);
+        }
+
+        for child in &self.children {
+            child.collect_nodes_ns(namespace_uri, local, max, scope, results);
+        }
+        scope.truncate(outer_len);
+    }
+
+    // `scope` holds the declarations in effect, this node's included
+    fn is_in_namespace(&self, namespace_uri: &str, local: &str, scope: &[(&str, &str)]) -> bool {
+        self.local_name() == local
+            && resolve_namespace(scope, self.prefix().unwrap_or("")) == Some(namespace_uri)
+    }
+
+    /// Like `get_nodes`, but matches any of `tags` in a single walk. Results
+    /// are in document order. Tags may be `*` or in Clark notation, as in
+    /// `get_nodes`.
+    pub fn get_nodes_any(&self, tags: &[&str]) -> Vec<&XmlNode> {
+        let mut results = Vec::new();
+        self.collect_nodes_any(tags, &mut Vec::new(), &mut results);
+        results
+    }
+
+    fn collect_nodes_any<'a>(
+        &'a self,
+        tags: &[&str],
+        scope: &mut Vec<(&'a str, &'a str)>,
+        results: &mut Vec<&'a XmlNode>,
+    ) {
+        let outer_len = scope.len();
+        scope.extend(namespace_declarations(&self.attributes));
+
+        let matches = |tag: &&str| match split_clark_name(tag) {
+            Some((namespace_uri, local)) => {
+                self.is_element() && self.is_in_namespace(namespace_uri, local, scope)
+            }
+            None => self.is_named(tag),
+        };
+        if tags.iter().any(matches) {
+            results.push(self);
+        }
+
+        for child in &self.children {
+            child.collect_nodes_any(tags, scope, results);
+        }
+        scope.truncate(outer_len);
+    }
+
+    /// Compares name, content, attributes (in order) and children
+    /// recursively; parse-time metadata such as inherited `xml:lang` is
+    /// ignored. `parse_xml(&node.to_xml_string())` is always structurally
+    /// equal to `node` for a parsed tree.
+    pub fn structurally_equal(&self, other: &XmlNode) -> bool {
+        self.name == other.name
+            && self.content == other.content
+            && self.attributes == other.attributes
+            && self.children.len() == other.children.len()
+            && self
+                .children
+                .iter()
+                .zip(&other.children)
+                .all(|(a, b)| a.structurally_equal(b))
+    }
+
+    /// Compares two trees as the same document in different formatting:
+    /// comment nodes are skipped, runs of whitespace in content count as one
+    /// space and leading or trailing whitespace is ignored, and attributes
+    /// may come in any order. Names, attribute values and the order of the
+    /// remaining children must match exactly.
+    pub fn semantically_equal(&self, other: &XmlNode) -> bool {
+        let mut attributes = self.attributes.iter().collect::<Vec<_>>();
+        let mut other_attributes = other.attributes.iter().collect::<Vec<_>>();
+        attributes.sort();
+        other_attributes.sort();
+
+        let mut children = self.children.iter().filter(|child| child.name != "#comment");
+        let mut other_children = other.children.iter().filter(|child| child.name != "#comment");
+
+        self.name == other.name
+            && self.content.split_whitespace().eq(other.content.split_whitespace())
+            && attributes == other_attributes
+            && loop {
+                match (children.next(), other_children.next()) {
+                    (Some(a), Some(b)) if a.semantically_equal(b) => continue,
+                    (None, None) => break true,
+                    _ => break false,
+                }
+            }
+    }
+
+    /// Predictable serialization for hashing and comparison: attributes sorted
+    /// by name, always double-quoted, no whitespace between tags, empty
+    /// elements written as `<x></x>`, comments dropped and CDATA sections
+    /// replaced by their escaped text. Not a full implementation of XML C14N.
+    pub fn canonicalize(&self) -> String {
+        let mut out = String::new();
+        self.write_canonical(&mut out);
+        out
+    }
+
+    fn write_canonical(&self, out: &mut String) {
+        match self.name.as_str() {
+            "#comment" => {}
+            "#pi" => out.push_str(&self.content),
+            "#cdata" => {
+                let text = self.cdata_text();
+                out.push_str(&escape_text(&text).replace('>', "&gt;"));
+            }
+            "#text" => out.push_str(&escape_text(&self.content).replace('>', "&gt;")),
+            _ => {
+                let mut attributes = self.attributes.iter().collect::<Vec<_>>();
+                attributes.sort_by(|a, b| a.0.cmp(&b.0));
+
+                out.push('<');
+                out.push_str(&self.name);
+                for (k, v) in attributes {
+                    out.push_str(&format!(" {}=\"{}\"", k, escape_attribute(v)));
+                }
+                out.push('>');
+                out.push_str(&escape_text(&self.content).replace('>', "&gt;"));
+
+                for child in &self.children {
+                    child.write_canonical(out);
+                }
+
+                out.push_str(&format!("</{}>", self.name));
+            }
+        }
+    }
+
+    /// Applies attribute-value normalization to every attribute in the subtree.
+    pub fn normalize_attribute_values(&mut self) {
+        for (_, value) in &mut self.attributes {
+            *value = normalize_attribute_value(value);
+        }
+
+        for child in &mut self.children {
+            child.normalize_attribute_values();
+        }
+    }
+
+    /// Attributes sorted by name (stable for repeated names); the node itself
+    /// keeps source order.
+    pub fn attributes_sorted(&self) -> Vec<(&str, &str)> {
+        let mut sorted = self
+            .attributes
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<Vec<_>>();
+        sorted.sort_by_key(|(k, _)| *k);
+        sorted
+    }
+
+    pub fn get_attribute(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of attribute `key` in source order. Only documents parsed
+    /// without `reject_duplicate_attributes` can hold more than one.
+    pub fn get_attributes(&self, key: &str) -> Vec<&str> {
+        self.attributes
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// Every element of the subtree (including this node) in document order,
+    /// paired with its path like `/root/items/item[2]/name`. Indices are
+    /// 1-based among same-named siblings and only written when the name
+    /// repeats. Paths are relative to this node, which becomes the root.
+    pub fn descendants_with_path(&self) -> Vec<(String, &XmlNode)> {
+        let mut results = Vec::new();
+        self.collect_paths(format!("/{}", self.name), &mut results);
+        results
+    }
+
+    fn collect_paths<'a>(&'a self, path: String, results: &mut Vec<(String, &'a XmlNode)>) {
+        results.push((path.clone(), self));
+
+        let mut totals: HashMap<&str, usize> = HashMap::new();
+        for child in self.children.iter().filter(|child| child.is_element()) {
+            *totals.entry(child.name.as_str()).or_default() += 1;
+        }
+
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for child in self.children.iter().filter(|child| child.is_element()) {
+            let index = seen.entry(child.name.as_str()).or_default();
+            *index += 1;
+            let segment = if totals[child.name.as_str()] > 1 {
+                format!("{}[{}]", child.name, index)
+            } else {
+                child.name.clone()
+            };
+            child.collect_paths(format!("{}/{}", path, segment), results);
+        }
+    }
+
+    /// Checks every element of the subtree against `spec` and returns all
+    /// violations found, in document order.
+    pub fn assert_structure(&self, spec: &StructureSpec) -> Result<(), Vec<Violation>> {
+        let mut violations = Vec::new();
+        for (path, node) in self.descendants_with_path() {
+            let Some(rule) = spec.rules.get(&node.name) else {
+                continue;
+            };
+            for child in &rule.children {
+                if !node.children.iter().any(|c| &c.name == child) {
+                    violations.push(Violation::MissingChild {
+                        path: path.clone(),
+                        child: child.clone(),
+                    });
+                }
+            }
+            for attribute in &rule.attributes {
+                if node.get_attribute(attribute).is_none() {
+                    violations.push(Violation::MissingAttribute {
+                        path: path.clone(),
+                        attribute: attribute.clone(),
+                    });
+                }
+            }
+        }
+
+        if violations.is_empty() { Ok(()) } else { Err(violations) }
+    }
+
+    /// Infers the structure of the subtree in one pass: for every element
+    /// name, the child elements and attributes seen on it and how often.
+    pub fn infer_schema(&self) -> SchemaSketch {
+        let mut observed = Vec::new();
+        self.observe_schema(&mut observed);
+        SchemaSketch { elements: observed.into_iter().map(ObservedElement::finish).collect() }
+    }
+
+    fn observe_schema(&self, observed: &mut Vec<ObservedElement>) {
+        if !self.is_element() {
+            return;
+        }
+
+        let index = match observed.iter().position(|element| element.name == self.name) {
+            Some(index) => index,
+            None => {
+                observed.push(ObservedElement { name: self.name.clone(), ..Default::default() });
+                observed.len() - 1
+            }
+        };
+        let element = &mut observed[index];
+        element.occurrences += 1;
+        record_names(
+            &mut element.children,
+            self.children.iter().filter(|c| c.is_element()).map(|c| c.name.as_str()),
+        );
+        record_names(&mut element.attributes, self.attributes.iter().map(|(k, _)| k.as_str()));
+
+        for child in &self.children {
+            child.observe_schema(observed);
+        }
+    }
+
+    /// Flattens the subtree into `(path, value)` pairs in document order,
+    /// using the paths of `descendants_with_path`. Each element contributes
+    /// its attributes as `path/@name` first, then its content if it is a leaf.
+    pub fn flatten(&self) -> Vec<(String, String)> {
+        let mut pairs = Vec::new();
+        for (path, node) in self.descendants_with_path() {
+            for (name, value) in &node.attributes {
+                pairs.push((format!("{}/@{}", path, name), value.clone()));
+            }
+            if node.is_leaf() {
+                pairs.push((path, node.content.clone()));
+            }
+        }
+        pairs
+    }
+
+    /// Path of `target` (compared by identity) within this subtree.
+    pub fn get_path(&self, target: &XmlNode) -> Option<String> {
+        self.descendants_with_path()
+            .into_iter()
+            .find(|(_, node)| std::ptr::eq(*node, target))
+            .map(|(path, _)| path)
+    }
+
+    /// Inverse of `get_path`: follows a `/root/a/b[2]` path from this node.
+    /// A segment without an index selects the first match.
+    pub fn resolve_path(&self, path: &str) -> Option<&XmlNode> {
+        let positions = self.path_positions(path)?;
+        Some(positions.iter().fold(self, |node, &position| &node.children[position]))
+    }
+
+    /// Replaces the node at `path` (as in `resolve_path`) with `new_node`.
+    /// The path of this node itself, e.g. `/root`, replaces this node.
+    /// Returns false, leaving the tree unchanged, when the path matches nothing.
+    pub fn replace_at(&mut self, path: &str, new_node: XmlNode) -> bool {
+        let Some(positions) = self.path_positions(path) else {
+            return false;
+        };
+        let target = positions.iter().fold(self, |node, &position| &mut node.children[position]);
+        *target = new_node;
+        true
+    }
+
+    // indices into `children`, level by level, of the node at `path`
+    fn path_positions(&self, path: &str) -> Option<Vec<usize>> {
+        let mut segments = path.strip_prefix('/')?.split('/');
+        let (root, index) = split_path_segment(segments.next()?)?;
+        if root != self.name || index != 1 {
+            return None;
+        }
+
+        let mut positions = Vec::new();
+        let mut node = self;
+        for segment in segments {
+            let (name, index) = split_path_segment(segment)?;
+            let (position, child) = node
+                .children
+                .iter()
+                .enumerate()
+                .filter(|(_, child)| child.is_element() && child.name == name)
+                .nth(index - 1)?;
+            positions.push(position);
+            node = child;
+        }
+        Some(positions)
+    }
+
+    /// Every value of attribute `key` in the subtree, in document order.
+    pub fn collect_attribute(&self, key: &str) -> Vec<&str> {
+        let mut values = Vec::new();
+        self.collect_attribute_into(key, &mut values);
+        values
+    }
+
+    fn collect_attribute_into<'a>(&'a self, key: &str, values: &mut Vec<&'a str>) {
+        values.extend(self.attributes.iter().filter(|(k, _)| k == key).map(|(_, v)| v.as_str()));
+
+        for child in &self.children {
+            child.collect_attribute_into(key, values);
+        }
+    }
+
+    /// Maps every `id` attribute in the subtree to its element. When an id
+    /// repeats, the first element in document order is kept.
+    pub fn build_id_index(&self) -> HashMap<&str, &XmlNode> {
+        self.build_id_index_with_duplicates().0
+    }
+
+    /// Same as `build_id_index`, also returning each id that was seen more
+    /// than once (one entry per extra occurrence).
+    pub fn build_id_index_with_duplicates(&self) -> (HashMap<&str, &XmlNode>, Vec<&str>) {
+        let mut index = HashMap::new();
+        let mut duplicates = Vec::new();
+        self.collect_ids(&mut index, &mut duplicates);
+        (index, duplicates)
+    }
+
+    fn collect_ids<'a>(&'a self, index: &mut HashMap<&'a str, &'a XmlNode>, duplicates: &mut Vec<&'a str>) {
+        if let Some(id) = self.get_attribute("id") {
+            if index.contains_key(id) {
+                duplicates.push(id);
+            } else {
+                index.insert(id, self);
+            }
+        }
+
+        for child in &self.children {
+            child.collect_ids(index, duplicates);
+        }
+    }
+
+    /// Maps each direct child element's name to its content. Repeated names
+    /// keep the last value; children that have child elements themselves and
+    /// comment/CDATA nodes are skipped.
+    pub fn to_map(&self) -> HashMap<String, String> {
+        self.children
+            .iter()
+            .filter(|child| child.is_element())
+            .filter(|child| child.is_leaf())
+            .map(|child| (child.name.clone(), child.content.clone()))
+            .collect()
+    }
+
+    /// Effective language of the node: its own `xml:lang` attribute, otherwise
+    /// the one inherited from its ancestors at parse time. An empty
+    /// `xml:lang=""` clears the language.
+    pub fn lang(&self) -> Option<String> {
+        self.get_attribute("xml:lang")
+            .or(self.inherited_lang.as_deref())
+            .filter(|lang| !lang.is_empty())
+            .map(str::to_string)
+    }
+
+    /// Groups direct child elements by name, keeping document order within
+    /// each group. Comment and CDATA pseudo-nodes are left out.
+    pub fn group_children(&self) -> HashMap<&str, Vec<&XmlNode>> {
+        let mut groups: HashMap<&str, Vec<&XmlNode>> = HashMap::new();
+        for child in self.children.iter().filter(|child| child.is_element()) {
+            groups.entry(child.name.as_str()).or_default().push(child);
+        }
+        groups
+    }
+
+    /// False for the `#comment` and `#cdata` pseudo-nodes.
+    pub fn is_element(&self) -> bool {
+        !self.name.starts_with('#')
+    }
+
+    // comment and text nodes are never found by name in content lookups;
+    // CDATA and processing-instruction nodes are, with their raw text
+    fn is_comment_or_text(&self) -> bool {
+        matches!(self.name.as_str(), "#comment" | "#text")
+    }
+
+    /// True if any child is an element; pseudo-nodes do not count.
+    pub fn has_children(&self) -> bool {
+        self.children.iter().any(|child| child.is_element())
+    }
+
+    /// True if the node has no element children (it may still hold
+    /// content, comments or CDATA).
+    pub fn is_leaf(&self) -> bool {
+        !self.has_children()
+    }
+
+    /// True if the node has no content and no children of any kind,
+    /// including pseudo-nodes. Attributes are not considered.
+    pub fn is_empty_element(&self) -> bool {
+        self.content.is_empty() && self.children.is_empty()
+    }
+
+    /// The `Display` tree, cut off `max_depth` levels below this node. Elided
+    /// children are replaced by a `… (N more children)` marker.
+    pub fn display_preview(&self, max_depth: usize) -> String {
+        let opts = DisplayOptions { max_depth: Some(max_depth), ..DisplayOptions::default() };
+        self.display_with(&opts)
+    }
+
+    pub fn display_with(&self, opts: &DisplayOptions) -> String {
+        TreeView { node: self, opts }.to_string()
+    }
+
+    // `opts.max_depth` limits how many more levels are printed (None = unlimited)
+    fn display_node(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        indent: usize,
+        opts: &DisplayOptions,
+    ) -> std::fmt::Result {
+        let pad = "  ".repeat(indent);
+        let color = opts.color;
+
+        match self.name.as_str() {
+            "#comment" | "#cdata" | "#pi" => {
+                writeln!(f, "{}{}", pad, paint(&self.content, MARKUP_COLOR, color))
+            }
+            "#text" => writeln!(f, "{}{}", pad, self.content),
+            _ => {
+                write!(f, "{}{}", pad, paint(&format!("<{}", self.name), TAG_COLOR, color))?;
+
+                for (k, v) in &self.attributes {
+                    let value = format!("\"{}\"", v);
+                    write!(
+                        f,
+                        " {}={}",
+                        paint(k, ATTRIBUTE_COLOR, color),
+                        paint(&value, VALUE_COLOR, color)
+                    )?;
+                }
+                writeln!(f, "{}", paint(">", TAG_COLOR, color))?;
+
+                if !self.content.is_empty() {
+                    writeln!(f, "{}  {}", pad, self.content)?;
+                }
+
+                match opts.max_depth {
+                    Some(0) if !self.children.is_empty() => {
+                        writeln!(f, "{}  … ({} more children)", pad, self.children.len())?;
+                    }
+                    _ => {
+                        let child_opts = DisplayOptions {
+                            max_depth: opts.max_depth.map(|d| d.saturating_sub(1)),
+                            color,
+                        };
+                        for child in &self.children {
+                            child.display_node(f, indent + 3, &child_opts)?;
+                        }
+                    }
+                }
+
+                writeln!(f, "{}", paint(&format!("</{}>", self.name), TAG_COLOR, color))
+            }
+        }
+    }
+
+}
+
+/// A parsed document: the root element together with the comments and
+/// processing instructions (`#pi` nodes) found before and after it.
+#[derive(Debug, Default)]
+pub struct XmlDocument {
+    /// Pseudo-attributes of the `<?xml ...?>` declaration in source order,
+    /// e.g. `("encoding", "UTF-8")`; empty without a declaration.
+    pub declaration: Vec<(String, String)>,
+    pub prolog: Vec<XmlNode>,
+    pub root: XmlNode,
+    pub epilog: Vec<XmlNode>,
+    /// Problems the parser recovered from or let through, in source order.
+    pub warnings: Vec<Warning>,
+}
+
+impl XmlDocument {
+    /// The declaration as an `XmlDeclaration`, e.g. to write it again with
+    /// `SerializeOptions::emit_declaration`; `None` without a declaration.
+    pub fn xml_declaration(&self) -> Option<XmlDeclaration> {
+        if self.declaration.is_empty() {
+            return None;
+        }
+        let value = |name: &str| {
+            self.declaration.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+        };
+        Some(XmlDeclaration {
+            version: value("version").unwrap_or_else(|| "1.0".to_string()),
+            encoding: value("encoding"),
+            standalone: value("standalone").and_then(|v| match v.as_str() {
+                "yes" => Some(true),
+                "no" => Some(false),
+                _ => None,
+            }),
+        })
+    }
+}
+
+/// An `<?xml ...?>` declaration to write before the root element. Displays
+/// as e.g. `<?xml version="1.0" encoding="UTF-8"?>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlDeclaration {
+    pub version: String,
+    pub encoding: Option<String>,
+    pub standalone: Option<bool>,
+}
+
+impl Default for XmlDeclaration {
+    fn default() -> Self {
+        XmlDeclaration { version: "1.0".to_string(), encoding: None, standalone: None }
+    }
+}
+
+impl std::fmt::Display for XmlDeclaration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<?xml version=\"{}\"", self.version)?;
+        if let Some(encoding) = &self.encoding {
+            write!(f, " encoding=\"{}\"", encoding)?;
+        }
+        if let Some(standalone) = self.standalone {
+            write!(f, " standalone=\"{}\"", if standalone { "yes" } else { "no" })?;
+        }
+        f.write_str("?>")
+    }
+}
+
+/// A recovered problem, located at the line and column where it was detected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WarningKind {
+    /// `<name>` was closed implicitly by an ancestor's closing tag or by the
+    /// end of the document (`auto_close`).
+    AutoClosed { name: String },
+    /// The element repeats attribute `name`; both are kept (unless
+    /// `reject_duplicate_attributes` turns this into an error).
+    DuplicateAttribute { name: String },
+    /// A comment contains `--` or ends in `-` (unless `strict_comments`
+    /// turns this into an error).
+    InvalidComment,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            WarningKind::AutoClosed { name } => write!(f, "Auto-closed <{}>", name)?,
+            WarningKind::DuplicateAttribute { name } => write!(f, "Duplicate attribute '{}'", name)?,
+            WarningKind::InvalidComment => write!(f, "Comment contains '--'")?,
+        }
+        write!(f, " at line {}, column {}", self.line, self.column)
+    }
+}
+
+// pre-order walk over the nodes still to visit, next one last
+struct Descendants<'a> {
+    stack: Vec<&'a XmlNode>,
+}
+
+impl<'a> Iterator for Descendants<'a> {
+    type Item = &'a XmlNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.stack.extend(node.children.iter().rev());
+        Some(node)
+    }
+}
+
+// `tag[@a][@b='v']`, see `XmlNode::matches`
+struct Selector<'s> {
+    name: &'s str,
+    predicates: Vec<Predicate<'s>>,
+}
+
+enum Predicate<'s> {
+    Has(&'s str),
+    Equals(&'s str, &'s str),
+    Not(Box<Predicate<'s>>),
+}
+
+impl Predicate<'_> {
+    fn holds(&self, node: &XmlNode) -> bool {
+        match self {
+            Predicate::Has(attr) => node.get_attribute(attr).is_some(),
+            Predicate::Equals(attr, value) => node.get_attribute(attr) == Some(*value),
+            Predicate::Not(predicate) => !predicate.holds(node),
+        }
+    }
+}
+
+impl Selector<'_> {
+    fn matches(&self, node: &XmlNode) -> bool {
+        node.is_element()
+            && (self.name == "*" || self.name == node.name)
+            && self.predicates.iter().all(|predicate| predicate.holds(node))
+    }
+}
+
+fn parse_selector(selector: &str) -> Option<Selector<'_>> {
+    let selector = selector.trim();
+    let (name, mut rest) = selector.split_at(selector.find('[').unwrap_or(selector.len()));
+    if name != "*" && !is_valid_name(name) {
+        return None;
+    }
+
+    let mut predicates = Vec::new();
+    while !rest.is_empty() {
+        let body = rest.strip_prefix('[')?;
+        let end = predicate_end(body)?;
+        predicates.push(parse_predicate(body[..end].trim())?);
+        rest = &body[end + 1..];
+    }
+    Some(Selector { name, predicates })
+}
+
+// index of the `]` closing a predicate body, skipping quoted values
+fn predicate_end(body: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, ch) in body.char_indices() {
+        match (quote, ch) {
+            (None, '\'' | '"') => quote = Some(ch),
+            (Some(open), _) if ch == open => quote = None,
+            (None, ']') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_predicate(body: &str) -> Option<Predicate<'_>> {
+    if let Some(inner) = body.strip_prefix("not(") {
+        let inner = inner.strip_suffix(')')?.trim();
+        return parse_predicate(inner).map(|predicate| Predicate::Not(Box::new(predicate)));
+    }
+
+    let body = body.strip_prefix('@')?;
+    let Some((attr, value)) = body.split_once('=') else {
+        return is_valid_name(body).then_some(Predicate::Has(body));
+    };
+
+    let (attr, value) = (attr.trim(), value.trim());
+    let quote = value.chars().next().filter(|c| matches!(c, '\'' | '"'))?;
+    let value = value.strip_prefix(quote)?.strip_suffix(quote)?;
+    is_valid_name(attr).then_some(Predicate::Equals(attr, value))
+}
+
+#[derive(Debug, Clone)]
+pub enum ParseError {
+    TagMismatch { opening: String, ending: String },
+
+    /// `expected` and `unexpected` are the grammar rules pest wanted, or
+    /// ruled out, at the failure point. `pest` keeps the grammar's own error,
+    /// with its position and rendered snippet, when the failure came from
+    /// the grammar; the pest error is also the error's `source()`.
+    SyntaxError {
+        expected: Vec<String>,
+        unexpected: Vec<String>,
+        pest: Option<Box<pest::error::Error<Rule>>>,
+    },
+
+    EmptyInput,
+
+    IoError(Arc<io::Error>),
+
+    MisplacedDeclaration { line: usize },
+
+    InvalidComment { line: usize, column: usize },
+
+    DuplicateAttribute { name: String, line: usize, column: usize },
+
+    /// Only produced by `XmlNode::from_json` (`json` feature); the variant
+    /// exists either way so the enum does not change with the features.
+    InvalidJson { message: String },
+
+    InvalidName { name: String },
+
+    UndeclaredPrefix { prefix: String, element: String },
+
+    InFile { path: String, source: Box<ParseError> },
+
+    InvalidCharacter { code: u32, version: String, line: usize, column: usize },
+
+    InvalidEntity { entity: String, line: usize, column: usize },
+
+    InvalidAttributeValue { name: String, line: usize, column: usize },
+
+    LimitExceeded { limit: &'static str, max: usize },
+
+    InternalError{message: String},
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ParseError::*;
+        match self {
+            TagMismatch { opening, ending } => {
+                write!(f, "Tag mismatch: opening tag <{}>, ending tag </{}>", opening, ending)
+            }
+            SyntaxError { expected, unexpected, .. } => write!(
+                f,
+                "Unexpected structure or syntax error in XML{}{}",
+                expected_suffix(expected),
+                unexpected_suffix(unexpected)
+            ),
+            EmptyInput => write!(f, "The document is empty"),
+            IoError(err) => write!(f, "File I/O error: {}", io_error_message(err)),
+            MisplacedDeclaration { line } => {
+                write!(f, "XML declaration must be at the start of the document, found at line {}", line)
+            }
+            InvalidComment { line, column } => {
+                write!(f, "Comment contains '--' at line {}, column {}", line, column)
+            }
+            DuplicateAttribute { name, line, column } => {
+                write!(f, "Duplicate attribute '{}' at line {}, column {}", name, line, column)
+            }
+            InvalidJson { message } => write!(f, "Invalid JSON tree: {}", message),
+            InvalidName { name } => write!(f, "Invalid XML name: '{}'", name),
+            UndeclaredPrefix { prefix, element } => {
+                write!(f, "Undeclared namespace prefix '{}' in <{}>", prefix, element)
+            }
+            InFile { path, source } => write!(f, "Error in {}: {}", path, source),
+            InvalidCharacter { code, version, line, column } => write!(
+                f,
+                "Character U+{:04X} is not allowed in XML {} at line {}, column {}",
+                code, version, line, column
+            ),
+            InvalidEntity { entity, line, column } => {
+                write!(f, "Invalid entity reference '{}' at line {}, column {}", entity, line, column)
+            }
+            InvalidAttributeValue { name, line, column } => write!(
+                f,
+                "Literal '<' in the value of attribute '{}' at line {}, column {}",
+                name, line, column
+            ),
+            LimitExceeded { limit, max } => write!(f, "Limit exceeded: {} is set to {}", limit, max),
+            InternalError { message } => write!(
+                f,
+                "Unexpected Internal Error: {}\nIf you see this error, something went wrong :/",
+                message
+            ),
+        }
+    }
+}
+
+// written by hand rather than derived so that `source()` is the pest error
+// itself, not the box around it
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::SyntaxError { pest: Some(pest), .. } => Some(&**pest),
+            ParseError::InFile { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+// `str::contains` comparing ASCII letters case-insensitively, without
+// allocating lowercased copies; other characters must match exactly
+fn contains_ignore_ascii_case(haystack: &str, needle: &str) -> bool {
+    needle.is_empty()
+        || haystack
+            .as_bytes()
+            .windows(needle.len())
+            .any(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+// nodes examined by the searches that stop early, so the tests can check
+// that they do; only compiled into the tests
+#[cfg(test)]
+thread_local! {
+    static VISITED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+fn count_visit() {
+    VISITED.with(|visited| visited.set(visited.get() + 1));
+}
+
+fn is_name_start_char(c: char) -> bool {
+    matches!(c,
+        ':' | 'A'..='Z' | '_' | 'a'..='z'
+        | '\u{C0}'..='\u{D6}' | '\u{D8}'..='\u{F6}' | '\u{F8}'..='\u{2FF}'
+        | '\u{370}'..='\u{37D}' | '\u{37F}'..='\u{1FFF}' | '\u{200C}'..='\u{200D}'
+        | '\u{2070}'..='\u{218F}' | '\u{2C00}'..='\u{2FEF}' | '\u{3001}'..='\u{D7FF}'
+        | '\u{F900}'..='\u{FDCF}' | '\u{FDF0}'..='\u{FFFD}' | '\u{10000}'..='\u{EFFFF}')
+}
+
+fn is_name_char(c: char) -> bool {
+    is_name_start_char(c)
+        || matches!(c,
+            '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}')
+}
+
+/// Checks `name` against the XML `Name` production (NameStartChar NameChar*).
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => is_name_start_char(first) && chars.all(is_name_char),
+        None => false,
+    }
+}
+
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+
+// (prefix, uri) pairs declared by `xmlns` / `xmlns:p` attributes; "" is the default namespace
+fn namespace_declarations(attributes: &[(String, String)]) -> impl Iterator<Item = (&str, &str)> {
+    attributes.iter().filter_map(|(k, v)| {
+        if k == "xmlns" {
+            Some(("", v.as_str()))
+        } else {
+            k.strip_prefix("xmlns:").map(|prefix| (prefix, v.as_str()))
+        }
+    })
+}
+
+// `{uri}local` -> (uri, local)
+fn split_clark_name(tag: &str) -> Option<(&str, &str)> {
+    tag.strip_prefix('{')?.split_once('}')
+}
+
+// innermost declaration wins; an empty URI undeclares the default namespace
+fn resolve_namespace<'a>(scope: &[(&str, &'a str)], prefix: &str) -> Option<&'a str> {
+    if prefix == "xml" {
+        return Some(XML_NAMESPACE);
+    }
+    scope
+        .iter()
+        .rev()
+        .find(|(declared, _)| *declared == prefix)
+        .map(|(_, uri)| *uri)
+        .filter(|uri| !uri.is_empty())
+}
+
+fn in_file(path: &Path, err: ParseError) -> ParseError {
+    ParseError::InFile { path: path.display().to_string(), source: Box::new(err) }
+}
+
+// OS errors are described by their kind, so the message reads the same on
+// every platform ("entity not found" rather than the OS wording)
+fn io_error_message(err: &io::Error) -> String {
+    if err.raw_os_error().is_some() {
+        err.kind().to_string()
+    } else {
+        err.to_string()
+    }
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>, Base64Error> {
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    let mut symbols = 0;
+    let mut padding = 0;
+
+    for (position, ch) in text.char_indices() {
+        let value = match ch {
+            _ if ch.is_ascii_whitespace() => continue,
+            '=' => {
+                padding += 1;
+                symbols += 1;
+                continue;
+            }
+            // nothing but padding may follow padding
+            _ if padding > 0 => return Err(Base64Error::InvalidCharacter { ch, position }),
+            'A'..='Z' => ch as u32 - 'A' as u32,
+            'a'..='z' => ch as u32 - 'a' as u32 + 26,
+            '0'..='9' => ch as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            _ => return Err(Base64Error::InvalidCharacter { ch, position }),
+        };
+
+        buffer = (buffer << 6) | value;
+        bits += 6;
+        symbols += 1;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    if symbols % 4 != 0 || padding > 2 {
+        return Err(Base64Error::InvalidLength { symbols });
+    }
+    Ok(bytes)
+}
+
+// XML 1.0, 2.5: the text of a comment must not contain `--` or end with `-`
+fn is_valid_comment(comment: &str) -> bool {
+    let text = comment
+        .strip_prefix("<!--")
+        .and_then(|c| c.strip_suffix("-->"))
+        .unwrap_or(comment);
+    !text.contains("--") && !text.ends_with('-')
+}
+
+// `name[n]` -> (name, n); a bare `name` means index 1
+fn split_path_segment(segment: &str) -> Option<(&str, usize)> {
+    match segment.split_once('[') {
+        Some((name, rest)) => {
+            let index = rest.strip_suffix(']')?.parse::<usize>().ok()?;
+            (index > 0).then_some((name, index))
+        }
+        None => Some((segment, 1)),
+    }
+}
+
+/// Attribute-value normalization for CDATA-typed attributes (XML 1.0, 3.3.3):
+/// `\r\n`, `\t`, `\n` and `\r` each become a single space. Without a DTD
+/// every attribute is CDATA-typed, so runs of spaces are not collapsed.
+pub fn normalize_attribute_value(value: &str) -> String {
+    value
+        .replace("\r\n", " ")
+        .replace(['\t', '\n', '\r'], " ")
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SerializeOptions {
+    /// Write element content as a CDATA section instead of plain text once it
+    /// contains at least this many markup characters (`<`, `>`, `&`).
+    pub cdata_threshold: Option<usize>,
+    /// Write elements without content or children as `<x/>` instead of
+    /// `<x></x>`. The tree does not remember which form the source used.
+    pub collapse_empty: bool,
+    /// Write attributes sorted by name, keeping only the first of repeated
+    /// names. The tree itself keeps source order.
+    pub sort_attributes: bool,
+    /// Write this declaration on its own line before the element; `None`
+    /// (the default) writes just the element, as a fragment.
+    pub emit_declaration: Option<XmlDeclaration>,
+}
+
+fn markup_char_count(text: &str) -> usize {
+    text.chars().filter(|c| matches!(c, '<' | '>' | '&')).count()
+}
+
+// a literal `]]>` cannot appear inside CDATA, so it is split across two sections
+fn wrap_cdata(text: &str) -> String {
+    format!("<![CDATA[{}]]>", text.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+/// One level of indentation in pretty-printed output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    Spaces(usize),
+    Tabs,
+}
+
+impl Default for Indent {
+    fn default() -> Self {
+        Indent::Spaces(2)
+    }
+}
+
+impl Indent {
+    /// Guesses the indentation of `source` from its first indented lines:
+    /// tabs if most of them start with a tab, otherwise the smallest number
+    /// of leading spaces. `None` if no line is indented.
+    pub fn detect(source: &str) -> Option<Indent> {
+        let sample = source
+            .lines()
+            .filter(|line| !line.trim().is_empty() && line.starts_with([' ', '\t']))
+            .take(20)
+            .collect::<Vec<&str>>();
+        if sample.is_empty() {
+            return None;
+        }
+
+        let tabbed = sample.iter().filter(|line| line.starts_with('\t')).count();
+        if tabbed * 2 >= sample.len() {
+            return Some(Indent::Tabs);
+        }
+        sample
+            .iter()
+            .map(|line| line.len() - line.trim_start_matches(' ').len())
+            .filter(|&width| width > 0)
+            .min()
+            .map(Indent::Spaces)
+    }
+
+    fn unit(&self) -> String {
+        match self {
+            Indent::Spaces(width) => " ".repeat(*width),
+            Indent::Tabs => "\t".to_string(),
+        }
+    }
+}
+
+/// Settings for text extraction (`inner_text_with`, `get_contents_of_with`).
+/// By default comments are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct TextOptions {
+    pub include_comments: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PrettyOptions {
+    pub indent: Indent,
+    /// Same as `SerializeOptions::sort_attributes`.
+    pub sort_attributes: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Apply `normalize_attribute_value` to every attribute while parsing.
+    pub normalize_attribute_values: bool,
+    /// Keep content exactly as written, including whitespace between tags.
+    pub preserve_whitespace: bool,
+    /// Maximum element nesting depth; the root element is at depth 1.
+    pub max_depth: Option<usize>,
+    /// Maximum number of attributes on one element (the pseudo-attributes
+    /// of the `<?xml ...?>` declaration count too).
+    pub max_attributes_per_element: Option<usize>,
+    /// Fail with `DuplicateAttribute` when an element repeats an attribute.
+    pub reject_duplicate_attributes: bool,
+    /// Join separate content runs of an element (text split by child nodes)
+    /// with a single space instead of gluing them together. Has no effect
+    /// with `preserve_whitespace`, which keeps the original text.
+    pub separate_content_runs: bool,
+    /// Reject comments containing `--` (or ending in `-`) with `InvalidComment`.
+    pub strict_comments: bool,
+    /// Fail with `UndeclaredPrefix` when an element or attribute uses a
+    /// namespace prefix with no `xmlns:prefix` declaration in scope.
+    pub require_declared_namespaces: bool,
+    /// Recover from a closing tag that matches an ancestor rather than the
+    /// innermost open element, as browsers do: the elements in between are
+    /// closed implicitly. Elements still open at the end of the document are
+    /// closed too. Each implicit close is reported in `XmlDocument::warnings`.
+    pub auto_close: bool,
+    /// Fail with `InvalidAttributeValue` when an attribute value contains a
+    /// literal `<`, which XML forbids; by default it is kept as written.
+    pub strict_attribute_values: bool,
+}
+
+/// Chained configuration for a reusable `Parser`.
+///
+/// ```
+/// use xml_parser::ParserBuilder;
+///
+/// let parser = ParserBuilder::new()
+///     .preserve_whitespace(true)
+///     .max_depth(100)
+///     .build();
+///
+/// let node = parser.parse("<root> spaced </root>").unwrap();
+/// assert_eq!(node.content, " spaced ");
+/// assert!(parser.parse("<a><b><c></c></b></a>").is_ok());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ParserBuilder {
+    opts: ParseOptions,
+}
+
+impl ParserBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn normalize_attribute_values(mut self, enabled: bool) -> Self {
+        self.opts.normalize_attribute_values = enabled;
+        self
+    }
+
+    pub fn preserve_whitespace(mut self, enabled: bool) -> Self {
+        self.opts.preserve_whitespace = enabled;
+        self
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.opts.max_depth = Some(depth);
+        self
+    }
+
+    pub fn max_attributes_per_element(mut self, max: usize) -> Self {
+        self.opts.max_attributes_per_element = Some(max);
+        self
+    }
+
+    pub fn reject_duplicate_attributes(mut self, enabled: bool) -> Self {
+        self.opts.reject_duplicate_attributes = enabled;
+        self
+    }
+
+    pub fn separate_content_runs(mut self, enabled: bool) -> Self {
+        self.opts.separate_content_runs = enabled;
+        self
+    }
+
+    pub fn strict_comments(mut self, enabled: bool) -> Self {
+        self.opts.strict_comments = enabled;
+        self
+    }
+
+    pub fn require_declared_namespaces(mut self, enabled: bool) -> Self {
+        self.opts.require_declared_namespaces = enabled;
+        self
+    }
+
+    pub fn auto_close(mut self, enabled: bool) -> Self {
+        self.opts.auto_close = enabled;
+        self
+    }
+
+    pub fn strict_attribute_values(mut self, enabled: bool) -> Self {
+        self.opts.strict_attribute_values = enabled;
+        self
+    }
+
+    pub fn build(self) -> Parser {
+        Parser { opts: self.opts }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Parser {
+    opts: ParseOptions,
+}
+
+impl Parser {
+    pub fn builder() -> ParserBuilder {
+        ParserBuilder::new()
+    }
+
+    pub fn options(&self) -> &ParseOptions {
+        &self.opts
+    }
+
+    pub fn parse(&self, input: &str) -> Result<XmlNode, ParseError> {
+        parse_xml_with(input, &self.opts)
+    }
+}
+
+impl From<pest::error::Error<Rule>> for ParseError {
+    fn from(err: pest::error::Error<Rule>) -> Self {
+        let rule_names = |rules: &[Rule]| rules.iter().map(|rule| format!("{:?}", rule)).collect();
+        let (expected, unexpected) = match &err.variant {
+            pest::error::ErrorVariant::ParsingError { positives, negatives } => {
+                (rule_names(positives), rule_names(negatives))
+            }
+            pest::error::ErrorVariant::CustomError { .. } => (Vec::new(), Vec::new()),
+        };
+        ParseError::SyntaxError { expected, unexpected, pest: Some(Box::new(err)) }
+    }
+}
+
+// structural errors found while building the tree carry no expected set
+fn syntax_error() -> ParseError {
+    ParseError::SyntaxError { expected: Vec::new(), unexpected: Vec::new(), pest: None }
+}
+
+fn expected_suffix(expected: &[String]) -> String {
+    if expected.is_empty() {
+        String::new()
+    } else {
+        format!(", expected one of: {}", expected.join(", "))
+    }
+}
+
+fn unexpected_suffix(unexpected: &[String]) -> String {
+    if unexpected.is_empty() {
+        String::new()
+    } else {
+        format!(", unexpected: {}", unexpected.join(", "))
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> Self {
+        ParseError::IoError(Arc::new(err))
+    }
+}
+
+/// Errors compare field by field, except `IoError`: two I/O errors are
+/// equal when their `io::ErrorKind` matches, whatever their message.
+impl PartialEq for ParseError {
+    fn eq(&self, other: &Self) -> bool {
+        use ParseError::*;
+        match (self, other) {
+            (TagMismatch { opening: a, ending: b }, TagMismatch { opening: c, ending: d }) => {
+                a == c && b == d
+            }
+            (
+                SyntaxError { expected: a, unexpected: b, .. },
+                SyntaxError { expected: c, unexpected: d, .. },
+            ) => a == c && b == d,
+            (EmptyInput, EmptyInput) => true,
+            (IoError(a), IoError(b)) => a.kind() == b.kind(),
+            (MisplacedDeclaration { line: a }, MisplacedDeclaration { line: b }) => a == b,
+            (
+                InvalidComment { line: a, column: b },
+                InvalidComment { line: c, column: d },
+            ) => a == c && b == d,
+            (
+                DuplicateAttribute { name: a, line: b, column: c },
+                DuplicateAttribute { name: d, line: e, column: f },
+            ) => a == d && b == e && c == f,
+            (InvalidJson { message: a }, InvalidJson { message: b }) => a == b,
+            (InvalidName { name: a }, InvalidName { name: b }) => a == b,
+            (
+                UndeclaredPrefix { prefix: a, element: b },
+                UndeclaredPrefix { prefix: c, element: d },
+            ) => a == c && b == d,
+            (LimitExceeded { limit: a, max: b }, LimitExceeded { limit: c, max: d }) => {
+                a == c && b == d
+            }
+            (InFile { path: a, source: b }, InFile { path: c, source: d }) => a == c && b == d,
+            (
+                InvalidEntity { entity: a, line: b, column: c },
+                InvalidEntity { entity: d, line: e, column: f },
+            ) => a == d && b == e && c == f,
+            (
+                InvalidAttributeValue { name: a, line: b, column: c },
+                InvalidAttributeValue { name: d, line: e, column: f },
+            ) => a == d && b == e && c == f,
+            (
+                InvalidCharacter { code: a, version: b, line: c, column: d },
+                InvalidCharacter { code: e, version: f, line: g, column: h },
+            ) => a == e && b == f && c == g && d == h,
+            (InternalError { message: a }, InternalError { message: b }) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Required child elements and attributes per element name, checked by
+/// `XmlNode::assert_structure`:
+///
+/// ```
+/// use xml_parser::{parse_xml, StructureSpec};
+///
+/// let spec = StructureSpec::new()
+///     .require_child("book", "title")
+///     .require_attribute("book", "id");
+/// let library = parse_xml(r#"<library><book id="1"><title>Dune</title></book></library>"#).unwrap();
+/// assert!(library.assert_structure(&spec).is_ok());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct StructureSpec {
+    rules: HashMap<String, ElementRule>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ElementRule {
+    children: Vec<String>,
+    attributes: Vec<String>,
+}
+
+impl StructureSpec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn require_child(mut self, element: &str, child: &str) -> Self {
+        self.rules.entry(element.to_string()).or_default().children.push(child.to_string());
+        self
+    }
+
+    pub fn require_attribute(mut self, element: &str, attribute: &str) -> Self {
+        self.rules
+            .entry(element.to_string())
+            .or_default()
+            .attributes
+            .push(attribute.to_string());
+        self
+    }
+}
+
+/// The structure inferred by `XmlNode::infer_schema`. Displays as one block
+/// per element, listing its attributes (`@name`) and children with their
+/// multiplicity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaSketch {
+    /// One entry per element name, in order of first appearance.
+    pub elements: Vec<ElementSketch>,
+}
+
+impl SchemaSketch {
+    pub fn element(&self, name: &str) -> Option<&ElementSketch> {
+        self.elements.iter().find(|element| element.name == name)
+    }
+}
+
+impl std::fmt::Display for SchemaSketch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for element in &self.elements {
+            writeln!(f, "<{}>", element.name)?;
+            for (name, multiplicity) in &element.attributes {
+                writeln!(f, "  {} @{}", multiplicity, name)?;
+            }
+            for (name, multiplicity) in &element.children {
+                writeln!(f, "  {} {}", multiplicity, name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Child elements and attributes observed on every element with this name,
+/// in order of first appearance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElementSketch {
+    pub name: String,
+    /// How many elements with this name were seen.
+    pub occurrences: usize,
+    pub children: Vec<(String, Multiplicity)>,
+    pub attributes: Vec<(String, Multiplicity)>,
+}
+
+/// How often a child or attribute appears on an element, written `1`, `?`
+/// or `*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Multiplicity {
+    /// Exactly once on every occurrence.
+    One,
+    /// At most once, missing on some occurrences.
+    Optional,
+    /// More than once on some occurrence.
+    Many,
+}
+
+impl std::fmt::Display for Multiplicity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Multiplicity::One => "1",
+            Multiplicity::Optional => "?",
+            Multiplicity::Many => "*",
+        })
+    }
+}
+
+// counts gathered by `XmlNode::infer_schema` for one element name
+#[derive(Default)]
+struct ObservedElement {
+    name: String,
+    occurrences: usize,
+    children: Vec<ObservedName>,
+    attributes: Vec<ObservedName>,
+}
+
+// a child or attribute name: on how many occurrences it appeared and the
+// most times it appeared on one
+struct ObservedName {
+    name: String,
+    present: usize,
+    max: usize,
+}
+
+impl ObservedElement {
+    fn finish(self) -> ElementSketch {
+        let occurrences = self.occurrences;
+        let multiplicities = |names: Vec<ObservedName>| {
+            names
+                .into_iter()
+                .map(|observed| {
+                    let multiplicity = if observed.max > 1 {
+                        Multiplicity::Many
+                    } else if observed.present < occurrences {
+                        Multiplicity::Optional
+                    } else {
+                        Multiplicity::One
+                    };
+                    (observed.name, multiplicity)
+                })
+                .collect()
+        };
+        ElementSketch {
+            name: self.name,
+            occurrences,
+            children: multiplicities(self.children),
+            attributes: multiplicities(self.attributes),
+        }
+    }
+}
+
+// adds the names seen on one occurrence of an element to `observed`
+fn record_names<'a>(observed: &mut Vec<ObservedName>, names: impl Iterator<Item = &'a str>) {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for name in names {
+        match counts.iter_mut().find(|(seen, _)| *seen == name) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((name, 1)),
+        }
+    }
+
+    for (name, count) in counts {
+        match observed.iter_mut().find(|o| o.name == name) {
+            Some(o) => {
+                o.present += 1;
+                o.max = o.max.max(count);
+            }
+            None => observed.push(ObservedName { name: name.to_string(), present: 1, max: count }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Violation {
+    #[error("{path} is missing a <{child}> child")]
+    MissingChild { path: String, child: String },
+
+    #[error("{path} is missing the '{attribute}' attribute")]
+    MissingAttribute { path: String, attribute: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Base64Error {
+    #[error("Invalid base64 character '{ch}' at byte {position}")]
+    InvalidCharacter { ch: char, position: usize },
+
+    #[error("Invalid base64 length: {symbols} symbols is not a multiple of 4")]
+    InvalidLength { symbols: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ContentError {
+    #[error("No <{tag}> node found")]
+    NotFound { tag: String },
+
+    #[error("Every <{tag}> node is empty")]
+    Empty { tag: String },
+}
+
+pub fn parse_xml(input: &str) -> Result<XmlNode, ParseError> {
+    parse_xml_with(input, &ParseOptions::default())
+}
+
+pub fn parse_xml_with(input: &str, opts: &ParseOptions) -> Result<XmlNode, ParseError> {
+    parse_document_with(input, opts).map(|document| document.root)
+}
+
+/// Like `parse_xml_with`, but also returns the warnings for the problems
+/// the parser let through, e.g. duplicate attributes or comments containing
+/// `--` when they are not rejected, and elements closed by `auto_close`.
+pub fn parse_xml_with_warnings(
+    input: &str,
+    opts: &ParseOptions,
+) -> Result<(XmlNode, Vec<Warning>), ParseError> {
+    parse_document_with(input, opts).map(|document| (document.root, document.warnings))
+}
+
+pub fn parse_document(input: &str) -> Result<XmlDocument, ParseError> {
+    parse_document_with(input, &ParseOptions::default())
+}
+
+/// Like `parse_xml_with`, but also keeps the top-level comments and
+/// processing instructions surrounding the root element.
+pub fn parse_document_with(input: &str, opts: &ParseOptions) -> Result<XmlDocument, ParseError> {
+    TreeBuilder::new(opts).build_document(document_pairs(input, document_rule(opts))?)
+}
+
+/// Where the time of one parse went, from `parse_xml_timed`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseStats {
+    /// Running the grammar and the character checks over the input.
+    pub grammar_time: Duration,
+    /// Building the `XmlNode` tree from the grammar's output.
+    pub build_time: Duration,
+    /// Nodes in the tree, comment, CDATA and processing-instruction nodes
+    /// included.
+    pub node_count: usize,
+    /// Length of the input in bytes.
+    pub bytes: usize,
+}
+
+/// Like `parse_xml`, but also measures the grammar and tree-building phases.
+pub fn parse_xml_timed(input: &str) -> Result<(XmlNode, ParseStats), ParseError> {
+    let opts = ParseOptions::default();
+
+    let start = Instant::now();
+    let pairs = document_pairs(input, document_rule(&opts))?;
+    let grammar_time = start.elapsed();
+
+    let start = Instant::now();
+    let root = TreeBuilder::new(&opts).build_document(pairs)?.root;
+    let build_time = start.elapsed();
+
+    let stats = ParseStats {
+        grammar_time,
+        build_time,
+        node_count: root.node_count(),
+        bytes: input.len(),
+    };
+    Ok((root, stats))
+}
+
+// the flat `tokens` rule lets `auto_close` recover from bad nesting
+fn document_rule(opts: &ParseOptions) -> Rule {
+    if opts.auto_close { Rule::tokens } else { Rule::xml }
+}
+
+/// Parses `input` with default options, calling `observer` for every element
+/// as soon as it is completed (children before their parent). The observer
+/// receives the names of the element's ancestors, outermost first.
+pub fn parse_with_observer(
+    input: &str,
+    observer: &mut impl FnMut(&[&str], &XmlNode),
+) -> Result<XmlNode, ParseError> {
+    let opts = ParseOptions::default();
+    let root = document_pairs(input, Rule::xml)?
+        .find(|p| p.as_rule() == Rule::element)
+        .ok_or_else(syntax_error)?;
+    TreeBuilder::with_observer(&opts, observer).parse_element(root, None)
+}
+
+// the top-level pairs of a document: declaration, prolog, root element, epilog
+fn document_pairs(input: &str, rule: Rule) -> Result<pest::iterators::Pairs<'_, Rule>, ParseError> {
+    // only XML whitespace counts: U+00A0 and the like are content, and an error
+    if input[document_start(input)..].trim_matches([' ', '\t', '\n', '\r']).is_empty() {
+        return Err(ParseError::EmptyInput);
+    }
+
+    let mut parsed = Grammar::parse(rule, input)
+        .map_err(|e| misplaced_declaration(input, &e).unwrap_or_else(|| e.into()))?;
+
+    let root = parsed.next().ok_or_else(syntax_error)?;
+
+    // the declaration must be the very first thing, optionally after a BOM
+    let declaration = root.clone().into_inner().find(|p| p.as_rule() == Rule::declaration);
+    if let Some(declaration) = &declaration
+        && declaration.as_span().start() != document_start(input)
+    {
+        let (line, _) = declaration.as_span().start_pos().line_col();
+        return Err(ParseError::MisplacedDeclaration { line });
+    }
+
+    let version = declaration.and_then(declared_version).unwrap_or("1.0");
+    check_characters(input, version)?;
+
+    Ok(root.into_inner())
+}
+
+// the `version` pseudo-attribute of an `<?xml ...?>` declaration
+fn declared_version(declaration: pest::iterators::Pair<'_, Rule>) -> Option<&str> {
+    declaration.into_inner().find_map(|attribute| {
+        let mut inner = attribute.into_inner();
+        let name = inner.next()?.as_str();
+        let value = inner.next()?.as_str().trim_matches('"');
+        (name == "version").then_some(value)
+    })
+}
+
+// XML 1.0 forbids C0 controls other than tab, CR and LF, written or
+// referenced. XML 1.1 allows them (except NUL) as character references only,
+// and also restricts U+007F..U+009F apart from U+0085 to references.
+fn check_characters(input: &str, version: &str) -> Result<(), ParseError> {
+    let is_xml11 = version == "1.1";
+    let is_control = |c: u32| c < 0x20 && !matches!(c, 0x9 | 0xA | 0xD);
+    let is_restricted = |c: u32| is_xml11 && (0x7F..=0x9F).contains(&c) && c != 0x85;
+    let literal_allowed = |c: u32| !(is_control(c) || is_restricted(c));
+    // `&#0;` is never allowed and is left to `decode_entities`
+    let reference_allowed = |c: u32| c == 0 || is_xml11 || !is_control(c);
+
+    let invalid = |code: u32, offset: usize| {
+        let (line, column) = pest::Position::new(input, offset)
+            .map_or((0, 0), |position| position.line_col());
+        ParseError::InvalidCharacter { code, version: version.to_string(), line, column }
+    };
+
+    for (offset, ch) in input.char_indices() {
+        if !literal_allowed(ch as u32) {
+            return Err(invalid(ch as u32, offset));
+        }
+    }
+
+    let mut rest = input;
+    while let Some(start) = rest.find(['&', '<']) {
+        let offset = input.len() - rest.len() + start;
+        let tail = &rest[start..];
+        // references are not recognised inside comments, CDATA sections and
+        // processing instructions
+        let skip_to = if tail.starts_with("<!--") {
+            tail.find("-->")
+        } else if tail.starts_with("<![CDATA[") {
+            tail.find("]]>")
+        } else if tail.starts_with("<?") {
+            tail.find("?>")
+        } else {
+            None
+        };
+        if let Some(end) = skip_to {
+            rest = &tail[end..];
+            continue;
+        }
+
+        if let Some(reference) = tail.strip_prefix("&#")
+            && let Some(end) = reference.find(';')
+        {
+            let number = &reference[..end];
+            let code = match number.strip_prefix('x') {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => number.parse::<u32>().ok(),
+            };
+            if let Some(code) = code
+                && !reference_allowed(code)
+            {
+                return Err(invalid(code, offset));
+            }
+        }
+        rest = &tail[1..];
+    }
+    Ok(())
+}
+
+// turns a grammar failure caused by a `<?xml ...?>` declaration appearing
+// later in the document into `MisplacedDeclaration`
+fn misplaced_declaration(input: &str, err: &pest::error::Error<Rule>) -> Option<ParseError> {
+    let position = match err.location {
+        pest::error::InputLocation::Pos(position) => position,
+        pest::error::InputLocation::Span((start, _)) => start,
+    };
+    // the grammar stops at the declaration's `<`, or just after it while
+    // looking for a tag name; failures anywhere else are ordinary syntax errors
+    let tag_start = [position, position.saturating_sub(1)].into_iter().find(|&start| {
+        input
+            .get(start..)
+            .and_then(|rest| rest.strip_prefix("<?xml"))
+            .is_some_and(|rest| rest.starts_with(['?', ' ', '\t', '\n', '\r']))
+    })?;
+    if tag_start == document_start(input) {
+        return None;
+    }
+
+    let line = input[..tag_start].matches('\n').count() + 1;
+    Some(ParseError::MisplacedDeclaration { line })
+}
+
+// offset of the first character after an optional byte order mark
+fn document_start(input: &str) -> usize {
+    if input.starts_with('\u{FEFF}') { '\u{FEFF}'.len_utf8() } else { 0 }
+}
+
+/// Parses a stream of concatenated XML documents, yielding one tree per
+/// top-level document. The reader is consumed incrementally: a document is
+/// yielded as soon as its root element is closed. Whitespace, comments and
+/// processing instructions between documents are skipped; an empty stream
+/// yields nothing. Anything else that does not form a document yields an
+/// error and ends the iteration.
+pub fn parse_stream<R: Read>(reader: R) -> impl Iterator<Item = Result<XmlNode, ParseError>> {
+    parse_stream_with(reader, &ParseOptions::default())
+}
+
+/// Like `parse_stream`, parsing every document with the given options.
+pub fn parse_stream_with<R: Read>(
+    reader: R,
+    opts: &ParseOptions,
+) -> impl Iterator<Item = Result<XmlNode, ParseError>> + use<R> {
+    DocumentStream {
+        reader,
+        opts: opts.clone(),
+        buffer: Vec::new(),
+        scanned: 0,
+        resume: 0,
+        start: None,
+        open: Vec::new(),
+        eof: false,
+        done: false,
+    }
+}
+
+struct DocumentStream<R> {
+    reader: R,
+    opts: ParseOptions,
+    buffer: Vec<u8>,
+    // `buffer[..scanned]` has been scanned for the end of the current document
+    scanned: usize,
+    // where to continue looking for the end of a long comment, CDATA section
+    // or processing instruction that starts at `scanned`
+    resume: usize,
+    // start of the current document: its declaration, root element or
+    // whatever stray text comes first
+    start: Option<usize>,
+    // names of the elements open at `scanned`
+    open: Vec<Vec<u8>>,
+    eof: bool,
+    done: bool,
+}
+
+impl<R: Read> DocumentStream<R> {
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; 8192];
+        loop {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            return Ok(());
+        }
+    }
+
+    // scans the bytes read since the last call and returns the end of the
+    // current document once its root element is closed, or once a closing
+    // tag does not match so the error is reported without reading further
+    fn scan(&mut self) -> Option<usize> {
+        while self.scanned < self.buffer.len() {
+            let at = self.scanned;
+            let rest = &self.buffer[at..];
+
+            let text = rest.iter().position(|&b| b == b'<').unwrap_or(rest.len());
+            if text > 0 {
+                if self.start.is_none()
+                    && let Some(offset) = rest[..text].iter().position(|&b| !is_xml_whitespace(b))
+                {
+                    self.start = Some(at + offset);
+                }
+                self.scanned += text;
+                continue;
+            }
+
+            let terminator = if rest.starts_with(b"<!--") {
+                Some((4, &b"-->"[..]))
+            } else if rest.starts_with(b"<![CDATA[") {
+                Some((9, &b"]]>"[..]))
+            } else if rest.starts_with(b"<?") {
+                Some((2, &b"?>"[..]))
+            } else {
+                None
+            };
+
+            if let Some((prefix, terminator)) = terminator {
+                let from = self.resume.max(at + prefix);
+                let Some(found) = find_bytes(&self.buffer[from..], terminator) else {
+                    self.resume = self.buffer.len().saturating_sub(terminator.len() - 1).max(from);
+                    return None;
+                };
+                let markup = &self.buffer[at..from + found + terminator.len()];
+                let is_declaration = markup.starts_with(b"<?xml")
+                    && markup.get(5).is_some_and(|&b| b == b'?' || is_xml_whitespace(b));
+                // top-level comments and processing instructions are skipped
+                if self.start.is_none() && (markup.starts_with(b"<![CDATA[") || is_declaration) {
+                    self.start = Some(at);
+                }
+                self.scanned += markup.len();
+                continue;
+            }
+
+            // too short yet to tell a comment or CDATA section from other markup
+            if !self.eof && (rest.len() < 2 || b"<!--".starts_with(rest) || b"<![CDATA[".starts_with(rest)) {
+                return None;
+            }
+            let end = markup_end(rest)?;
+            let tag = &rest[..end];
+            self.scanned += end;
+            self.start.get_or_insert(at);
+
+            if let Some(name) = tag.strip_prefix(b"</") {
+                let name = tag_name(name);
+                if self.open.pop().is_none_or(|open| open != name) || self.open.is_empty() {
+                    return Some(self.scanned);
+                }
+            } else if tag.starts_with(b"<!") || tag.ends_with(b"/>") {
+                if self.open.is_empty() {
+                    return Some(self.scanned);
+                }
+            } else {
+                self.open.push(tag_name(&tag[1..]).to_vec());
+            }
+        }
+        None
+    }
+
+    fn parse(&self, end: usize) -> Result<XmlNode, ParseError> {
+        let bytes = &self.buffer[self.start.unwrap_or(self.scanned)..end];
+        let text = std::str::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        parse_document_with(text, &self.opts).map(|document| document.root)
+    }
+}
+
+impl<R: Read> Iterator for DocumentStream<R> {
+    type Item = Result<XmlNode, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some(end) = self.scan() {
+                let result = self.parse(end);
+                self.buffer.drain(..end);
+                self.scanned = 0;
+                self.resume = 0;
+                self.start = None;
+                self.open.clear();
+                self.done = result.is_err();
+                return Some(result);
+            }
+
+            if self.eof {
+                self.done = true;
+                if self.start.is_none() && self.scanned == self.buffer.len() {
+                    return None;
+                }
+                return Some(self.parse(self.buffer.len()));
+            }
+
+            if let Err(e) = self.fill() {
+                self.done = true;
+                return Some(Err(e.into()));
+            }
+        }
+        None
+    }
+}
+
+fn is_xml_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// length of the tag at the start of `markup`, skipping `>` inside quoted
+// attribute values; `None` while the tag is incomplete
+fn markup_end(markup: &[u8]) -> Option<usize> {
+    let mut quote = None;
+    for (i, &byte) in markup.iter().enumerate().skip(1) {
+        match quote {
+            Some(q) if byte == q => quote = None,
+            Some(_) => {}
+            None if byte == b'"' || byte == b'\'' => quote = Some(byte),
+            None if byte == b'>' => return Some(i + 1),
+            None => {}
+        }
+    }
+    None
+}
+
+// the element name at the start of a tag's bytes, after `<` or `</`
+fn tag_name(tag: &[u8]) -> &[u8] {
+    let end = tag.iter().position(|&b| is_xml_whitespace(b) || b == b'/' || b == b'>');
+    &tag[..end.unwrap_or(tag.len())]
+}
+
+type Attributes = Vec<(String, String)>;
+
+type Observer<'o> = &'o mut dyn FnMut(&[&str], &XmlNode);
+
+// an element whose closing tag has not been seen yet (`auto_close` mode)
+struct OpenElement {
+    node: XmlNode,
+    // `xml:lang` in effect inside the element
+    lang: Option<String>,
+    // length of `TreeBuilder::prefixes` before the element's declarations
+    scope_len: usize,
+}
+
+struct TreeBuilder<'i, 'o> {
+    opts: &'o ParseOptions,
+    // names of the open elements enclosing the one being parsed
+    ancestors: Vec<&'i str>,
+    // namespace prefixes declared by the open elements, innermost last
+    prefixes: Vec<String>,
+    observer: Option<Observer<'o>>,
+    warnings: Vec<Warning>,
+}
+
+impl<'i, 'o> TreeBuilder<'i, 'o> {
+    fn new(opts: &'o ParseOptions) -> Self {
+        TreeBuilder {
+            opts,
+            ancestors: Vec::new(),
+            prefixes: Vec::new(),
+            observer: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    fn with_observer(opts: &'o ParseOptions, observer: Observer<'o>) -> Self {
+        TreeBuilder {
+            observer: Some(observer),
+            ..Self::new(opts)
+        }
+    }
+
+    fn parse_element(
+        &mut self,
+        element: pest::iterators::Pair<'i, Rule>,
+        inherited_lang: Option<&str>,
+    ) -> Result<XmlNode, ParseError> {
+        let opts = self.opts;
+        if let Some(max_depth) = opts.max_depth
+            && self.ancestors.len() >= max_depth
+        {
+            return Err(ParseError::LimitExceeded { limit: "max_depth", max: max_depth });
+        }
+
+        let mut inner = element.into_inner();
+        let pair = inner.next().ok_or_else(syntax_error)?;
+
+        match pair.as_rule() {
+            Rule::full_element => {
+                let mut inner = pair.into_inner();
+                let opening = inner.next().ok_or_else(syntax_error)?;
+                let mut last_end = opening.as_span().end();
+                let (name_open, attrs) = parse_opening_tag(opening, opts, &mut self.warnings)?;
+                let scope_len = self.prefixes.len();
+                self.declare_prefixes(name_open, &attrs)?;
+                let lang = attrs
+                    .iter()
+                    .find(|(k, _)| k == "xml:lang")
+                    .map(|(_, v)| v.as_str())
+                    .or(inherited_lang);
+
+                let mut children = Vec::new();
+                let mut content = String::new();
+
+                self.ancestors.push(name_open);
+                while let Some(item) = inner.next() {
+                    if opts.preserve_whitespace {
+                        // implicit WHITESPACE between tokens is not part of any pair
+                        content.push_str(&item.get_input()[last_end..item.as_span().start()]);
+                        last_end = item.as_span().end();
+                    }
+
+                    match item.as_rule() {
+                        Rule::content if opts.preserve_whitespace => {
+                            content.push_str(&content_text(&item, opts)?)
+                        }
+                        Rule::content => {
+                            let text = content_text(&item, opts)?;
+                            if opts.separate_content_runs && !content.is_empty() && !text.is_empty() {
+                                content.push(' ');
+                            }
+                            content.push_str(&text);
+                        }
+                        Rule::element => {
+                            // the pairs left (closing tag included) bound the children to come
+                            if children.is_empty() {
+                                children.reserve(inner.len());
+                            }
+                            children.push(self.parse_element(item, lang)?)
+                        }
+                        Rule::closing_tag => {
+                            self.ancestors.pop();
+                            self.prefixes.truncate(scope_len);
+                            let name_close = item
+                                .into_inner()
+                                .next()
+                                .ok_or_else(syntax_error)?
+                                .as_str()
+                                .to_string();
+                            if name_close != name_open {
+                                return Err(ParseError::TagMismatch {
+                                    opening: name_open.to_string(),
+                                    ending: name_close,
+                                });
+                            }
+                            return Ok(self.complete(XmlNode {
+                                name: name_open.to_string(),
+                                attributes: attrs,
+                                content,
+                                children,
+                                inherited_lang: inherited_lang.map(str::to_string),
+                            }));
+                        }
+                        _ => {}
+                    }
+                }
+                Err(syntax_error())
+            }
+
+            Rule::empty_element_tag => {
+                let mut inner = pair.into_inner();
+                let name = inner.next().ok_or_else(syntax_error)?.as_str().to_string();
+                let attrs = parse_attributes(inner, opts, &mut self.warnings)?;
+                let scope_len = self.prefixes.len();
+                self.declare_prefixes(&name, &attrs)?;
+                self.prefixes.truncate(scope_len);
+                Ok(self.complete(XmlNode {
+                    name,
+                    attributes: attrs,
+                    content: String::new(),
+                    children: Vec::new(),
+                    inherited_lang: inherited_lang.map(str::to_string),
+                }))
+            }
+
+            _ => self.parse_misc(pair, inherited_lang),
+        }
+    }
+
+    // builds a document from the pairs of `document_rule`
+    fn build_document(
+        mut self,
+        pairs: pest::iterators::Pairs<'i, Rule>,
+    ) -> Result<XmlDocument, ParseError> {
+        let opts = self.opts;
+        if opts.auto_close {
+            return self.parse_tolerant(pairs);
+        }
+
+        let mut document = XmlDocument::default();
+        let mut seen_root = false;
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::element => {
+                    document.root = self.parse_element(pair, None)?;
+                    seen_root = true;
+                }
+                Rule::comment | Rule::pi if seen_root => {
+                    document.epilog.push(self.parse_misc(pair, None)?)
+                }
+                Rule::comment | Rule::pi => document.prolog.push(self.parse_misc(pair, None)?),
+                Rule::declaration => {
+                    document.declaration = parse_attributes(pair.into_inner(), opts, &mut self.warnings)?
+                }
+                _ => {}
+            }
+        }
+
+        document.warnings = self.warnings;
+        if seen_root { Ok(document) } else { Err(syntax_error()) }
+    }
+
+    // builds a document from the flat `tokens` rule, keeping the open
+    // elements on a stack so a closing tag can close several of them
+    fn parse_tolerant(
+        &mut self,
+        pairs: pest::iterators::Pairs<'i, Rule>,
+    ) -> Result<XmlDocument, ParseError> {
+        let opts = self.opts;
+        let mut document = XmlDocument::default();
+        let mut open: Vec<OpenElement> = Vec::new();
+        let mut root = None;
+        let mut last_end = 0;
+
+        for pair in pairs {
+            let span = pair.as_span();
+            if opts.preserve_whitespace
+                && let Some(top) = open.last_mut()
+            {
+                top.node.content.push_str(&pair.get_input()[last_end..span.start()]);
+            }
+            last_end = span.end();
+            let lang = open.last().and_then(|element| element.lang.clone());
+
+            match pair.as_rule() {
+                Rule::opening_tag | Rule::empty_element_tag if root.is_some() => {
+                    return Err(syntax_error());
+                }
+                Rule::opening_tag | Rule::empty_element_tag => {
+                    if let Some(max_depth) = opts.max_depth
+                        && self.ancestors.len() >= max_depth
+                    {
+                        return Err(ParseError::LimitExceeded { limit: "max_depth", max: max_depth });
+                    }
+
+                    let is_empty = pair.as_rule() == Rule::empty_element_tag;
+                    let (name, attrs) = parse_opening_tag(pair, opts, &mut self.warnings)?;
+                    let scope_len = self.prefixes.len();
+                    self.declare_prefixes(name, &attrs)?;
+                    let element = OpenElement {
+                        lang: attrs
+                            .iter()
+                            .find(|(k, _)| k == "xml:lang")
+                            .map(|(_, v)| v.clone())
+                            .or_else(|| lang.clone()),
+                        node: XmlNode {
+                            name: name.to_string(),
+                            attributes: attrs,
+                            inherited_lang: lang,
+                            ..XmlNode::default()
+                        },
+                        scope_len,
+                    };
+                    self.ancestors.push(name);
+                    open.push(element);
+                    if is_empty {
+                        self.close_element(&mut open, &mut root);
+                    }
+                }
+                Rule::closing_tag => {
+                    let (line, column) = span.start_pos().line_col();
+                    let name = pair.into_inner().next().ok_or_else(syntax_error)?.as_str();
+                    let Some(target) = open.iter().rposition(|element| element.node.name == name) else {
+                        return Err(match open.last() {
+                            Some(top) => ParseError::TagMismatch {
+                                opening: top.node.name.clone(),
+                                ending: name.to_string(),
+                            },
+                            None => syntax_error(),
+                        });
+                    };
+                    while open.len() > target + 1 {
+                        let name = self.close_element(&mut open, &mut root);
+                        let kind = WarningKind::AutoClosed { name };
+                        self.warnings.push(Warning { kind, line, column });
+                    }
+                    self.close_element(&mut open, &mut root);
+                }
+                Rule::content => match open.last_mut() {
+                    Some(top) if opts.preserve_whitespace => {
+                        top.node.content.push_str(&content_text(&pair, opts)?)
+                    }
+                    Some(top) => {
+                        let text = content_text(&pair, opts)?;
+                        let content = &mut top.node.content;
+                        if opts.separate_content_runs && !content.is_empty() && !text.is_empty() {
+                            content.push(' ');
+                        }
+                        content.push_str(&text);
+                    }
+                    None if pair.as_str().trim().is_empty() => {}
+                    None => return Err(syntax_error()),
+                },
+                Rule::comment | Rule::cdata | Rule::pi => {
+                    let is_cdata = pair.as_rule() == Rule::cdata;
+                    let node = self.parse_misc(pair, lang.as_deref())?;
+                    match open.last_mut() {
+                        Some(top) => top.node.children.push(node),
+                        None if is_cdata => return Err(syntax_error()),
+                        None if root.is_some() => document.epilog.push(node),
+                        None => document.prolog.push(node),
+                    }
+                }
+                Rule::declaration => {
+                    document.declaration = parse_attributes(pair.into_inner(), opts, &mut self.warnings)?;
+                }
+                Rule::EOI => {
+                    let (line, column) = span.start_pos().line_col();
+                    while !open.is_empty() {
+                        let name = self.close_element(&mut open, &mut root);
+                        let kind = WarningKind::AutoClosed { name };
+                        self.warnings.push(Warning { kind, line, column });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        document.root = root.ok_or_else(syntax_error)?;
+        document.warnings = std::mem::take(&mut self.warnings);
+        Ok(document)
+    }
+
+    // finishes the innermost open element, attaching it to its parent or
+    // making it the root; returns its name
+    fn close_element(&mut self, open: &mut Vec<OpenElement>, root: &mut Option<XmlNode>) -> String {
+        let Some(element) = open.pop() else {
+            return String::new();
+        };
+        self.ancestors.pop();
+        self.prefixes.truncate(element.scope_len);
+
+        let node = self.complete(element.node);
+        let name = node.name.clone();
+        match open.last_mut() {
+            Some(parent) => parent.node.children.push(node),
+            None => *root = Some(node),
+        }
+        name
+    }
+
+    // with `require_declared_namespaces`, brings the element's `xmlns:p`
+    // declarations into scope and checks every prefix it uses
+    fn declare_prefixes(&mut self, name: &str, attrs: &Attributes) -> Result<(), ParseError> {
+        if !self.opts.require_declared_namespaces {
+            return Ok(());
+        }
+        self.prefixes.extend(
+            namespace_declarations(attrs)
+                .filter(|(prefix, _)| !prefix.is_empty())
+                .map(|(prefix, _)| prefix.to_string()),
+        );
+
+        let used = std::iter::once(name)
+            .chain(attrs.iter().map(|(k, _)| k.as_str()))
+            .filter_map(|n| n.split_once(':').map(|(prefix, _)| prefix));
+        for prefix in used {
+            let predeclared = prefix == "xml" || prefix == "xmlns" || prefix.is_empty();
+            if !predeclared && !self.prefixes.iter().any(|p| p == prefix) {
+                return Err(ParseError::UndeclaredPrefix {
+                    prefix: prefix.to_string(),
+                    element: name.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // builds the pseudo-node for a comment, CDATA section or processing instruction
+    fn parse_misc(
+        &mut self,
+        pair: pest::iterators::Pair<'i, Rule>,
+        inherited_lang: Option<&str>,
+    ) -> Result<XmlNode, ParseError> {
+        let name = match pair.as_rule() {
+            Rule::comment if self.opts.strict_comments && !is_valid_comment(pair.as_str()) => {
+                let (line, column) = pair.as_span().start_pos().line_col();
+                return Err(ParseError::InvalidComment { line, column });
+            }
+            Rule::comment => {
+                if !is_valid_comment(pair.as_str()) {
+                    let (line, column) = pair.as_span().start_pos().line_col();
+                    self.warnings.push(Warning { kind: WarningKind::InvalidComment, line, column });
+                }
+                "#comment"
+            }
+            Rule::cdata => "#cdata",
+            Rule::pi => "#pi",
+            rule => {
+                return Err(ParseError::InternalError {
+                    message: format!("Unexpected rule: {:?}", rule),
+                });
+            }
+        };
+
+        Ok(XmlNode {
+            name: name.to_string(),
+            attributes: Vec::new(),
+            content: pair.as_str().to_string(),
+            children: Vec::new(),
+            inherited_lang: inherited_lang.map(str::to_string),
+        })
+    }
+
+    // hands a finished element to the observer, if any
+    fn complete(&mut self, node: XmlNode) -> XmlNode {
+        if let Some(observer) = self.observer.as_mut() {
+            observer(&self.ancestors, &node);
+        }
+        node
+    }
+}
+
+// the text of a `content` pair with entity and character references
+// decoded, trimmed unless whitespace is preserved
+fn content_text(
+    pair: &pest::iterators::Pair<'_, Rule>,
+    opts: &ParseOptions,
+) -> Result<String, ParseError> {
+    let raw = pair.as_str();
+    let (text, skipped) = if opts.preserve_whitespace {
+        (raw, 0)
+    } else {
+        (raw.trim(), raw.len() - raw.trim_start().len())
+    };
+    decode_at(pair, text, pair.as_span().start() + skipped)
+}
+
+// decodes `text`, which starts at byte `start` of the pair's input, so a bad
+// reference is reported at its own line and column
+fn decode_at(
+    pair: &pest::iterators::Pair<'_, Rule>,
+    text: &str,
+    start: usize,
+) -> Result<String, ParseError> {
+    decode_entities(text).map_err(|(offset, entity)| {
+        let (line, column) = line_col_at(pair, start + offset);
+        ParseError::InvalidEntity { entity, line, column }
+    })
+}
+
+fn line_col_at(pair: &pest::iterators::Pair<'_, Rule>, pos: usize) -> (usize, usize) {
+    pest::Position::new(pair.get_input(), pos).map_or((0, 0), |position| position.line_col())
+}
+
+// replaces `&amp;`, `&lt;`, `&gt;`, `&quot;`, `&apos;` and `&#...;` with the
+// characters they stand for; on failure returns the offset and text of the
+// offending reference. Character references must name a character of some
+// XML version: not NUL, a surrogate, U+FFFE, U+FFFF or beyond U+10FFFF.
+// Controls that only some versions allow are checked by `check_characters`.
+fn decode_entities(text: &str) -> Result<String, (usize, String)> {
+    if !text.contains('&') {
+        return Ok(text.to_string());
+    }
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let offset = text.len() - rest.len() + start;
+        let reference = &rest[start..];
+        let Some(end) = reference.find(';') else {
+            let entity = reference.split_whitespace().next().unwrap_or(reference);
+            return Err((offset, entity.to_string()));
+        };
+
+        let name = &reference[1..end];
+        let ch = match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => name.strip_prefix('#').and_then(|number| {
+                let code = match number.strip_prefix('x') {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => number.parse::<u32>().ok(),
+                };
+                code.filter(|c| !matches!(c, 0 | 0xFFFE | 0xFFFF)).and_then(char::from_u32)
+            }),
+        };
+        match ch {
+            Some(ch) => decoded.push(ch),
+            None => return Err((offset, reference[..=end].to_string())),
+        }
+        rest = &reference[end + 1..];
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+// escapes what cannot appear literally in element content: `&`, `<` and
+// the `>` of a `]]>` sequence
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace("]]>", "]]&gt;")
+}
+
+// escapes an attribute value for a double-quoted attribute; tabs and line
+// breaks become character references so that attribute-value normalization
+// does not turn them into spaces on the next parse
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+        .replace('\t', "&#9;")
+        .replace('\n', "&#10;")
+        .replace('\r', "&#13;")
+}
+
+fn parse_opening_tag<'i>(pair: pest::iterators::Pair<'i, Rule>, opts: &ParseOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<(&'i str, Attributes), ParseError> {
+
+    let mut inner = pair.into_inner();
+    let name = inner.next().ok_or_else(syntax_error)?.as_str();
+    let attrs = parse_attributes(inner, opts, warnings)?;
+    Ok((name, attrs))
+}
+
+fn parse_attributes<'a>(
+    pairs: impl Iterator<Item = pest::iterators::Pair<'a, Rule>>,
+    opts: &ParseOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<Attributes, ParseError> {
+    // every remaining pair of a tag is an attribute
+    let count = pairs.size_hint().0;
+    let mut attributes: Attributes = Vec::with_capacity(count);
+    // scanning `attributes` is cheapest for the few attributes most tags
+    // have; a set keeps the duplicate check linear on tags with many
+    let mut seen: Option<HashSet<&str>> = (count > 8).then(HashSet::new);
+    for attr in pairs {
+        if attr.as_rule() == Rule::attribute {
+            if let Some(max) = opts.max_attributes_per_element
+                && attributes.len() >= max
+            {
+                return Err(ParseError::LimitExceeded { limit: "max_attributes_per_element", max });
+            }
+            let position = attr.as_span().start_pos();
+            let mut parts = attr.into_inner();
+            let name = parts.next().ok_or_else(syntax_error)?.as_str();
+            let duplicate = match &mut seen {
+                Some(seen) => !seen.insert(name),
+                None => attributes.iter().any(|(k, _)| k == name),
+            };
+            let key = name.to_string();
+            let value_pair = parts.next().ok_or_else(syntax_error)?;
+            let raw = value_pair.as_str().trim_matches('"');
+            // the opening quote precedes the value
+            let start = value_pair.as_span().start() + 1;
+            if opts.strict_attribute_values
+                && let Some(offset) = raw.find('<')
+            {
+                let (line, column) = line_col_at(&value_pair, start + offset);
+                return Err(ParseError::InvalidAttributeValue { name: key, line, column });
+            }
+            // literal whitespace is normalized before references are decoded,
+            // so `&#10;` still yields a line break
+            let value = if opts.normalize_attribute_values {
+                decode_at(&value_pair, &normalize_attribute_value(raw), start)?
+            } else {
+                decode_at(&value_pair, raw, start)?
+            };
+            if duplicate {
+                let (line, column) = position.line_col();
+                if opts.reject_duplicate_attributes {
+                    return Err(ParseError::DuplicateAttribute { name: key, line, column });
+                }
+                let kind = WarningKind::DuplicateAttribute { name: key.clone() };
+                warnings.push(Warning { kind, line, column });
+            }
+            attributes.push((key, value));
+        }
+    }
+    Ok(attributes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(input: &str) -> XmlNode {
+        parse_xml(input).expect("expected valid XML")
+    }
+
+    // nodes examined by `f` in the searches that stop early
+    fn visits(f: impl FnOnce()) -> usize {
+        VISITED.with(|visited| visited.set(0));
+        f();
+        VISITED.with(|visited| visited.get())
+    }
+
+    fn parse_err(input: &str) -> ParseError {
+        parse_xml(input).unwrap_err()
+    }
+
+
+    #[test]
+    fn parses_empty_element() {
+        let xml = "<root></root>";
+        let result = parse_ok(xml);
+
+        assert_eq!(result.name, "root");
+        assert_eq!(result.content, "");
+        assert!(result.children.is_empty());
+    }
+
+    #[test]
+    fn parses_simple_element() {
+        let xml = "<root>hello</root>";
+        let result = parse_ok(xml);
+
+        assert_eq!(result.name, "root");
+        assert_eq!(result.content, "hello");
+        assert!(result.children.is_empty());
+    }
+
+    #[test]
+    fn ignores_whitespace_in_content() {
+        let xml = "<root>   s p a c e d   </root>";
+        let result = parse_ok(xml);
+
+        assert_eq!(result.content, "s p a c e d");
+    }
+
+    #[test]
+    fn parses_nested_elements() {
+        let xml = "<root><a>1</a><b>2</b></root>";
+        let result = parse_ok(xml);
+
+        assert_eq!(result.name, "root");
+        assert_eq!(result.children.len(), 2);
+        assert_eq!(result.children[0].name, "a");
+        assert_eq!(result.children[0].content, "1");
+        assert_eq!(result.children[1].name, "b");
+        assert_eq!(result.children[1].content, "2");
+    }
+
+    #[test]
+    fn parses_nested_elements_with_content() {
+        let xml = "<root>Content<a>1</a><b>2</b></root>";
+        let result = parse_ok(xml);
+        assert_eq!(result.name, "root");
+        assert_eq!(result.children.len(), 2);
+        assert_eq!(result.content, "Content");
+        assert_eq!(result.children[0].name, "a");
+        assert_eq!(result.children[0].content, "1");
+        assert_eq!(result.children[1].name, "b");
+        assert_eq!(result.children[1].content, "2");
+    }
+
+    #[test]
+    fn detects_empty_input() {
+        let xml = "";
+        match parse_err(xml) {
+            ParseError::EmptyInput => {}
+            other => panic!("expected EmptyInput, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn detects_whitespace_only_input() {
+        for xml in [" ", "\n\t\r\n  ", "\u{FEFF}\n"] {
+            assert_eq!(parse_err(xml), ParseError::EmptyInput, "{:?}", xml);
+        }
+        assert!(matches!(parse_err("  text  "), ParseError::SyntaxError { .. }));
+        for xml in ["\u{A0}", " \u{2003}\n", "\u{FEFF}\u{FEFF}"] {
+            assert!(matches!(parse_err(xml), ParseError::SyntaxError { .. }), "{:?}", xml);
+        }
+        assert!(matches!(parse_err("<!-- only a comment -->"), ParseError::SyntaxError { .. }));
+    }
+
+    #[test]
+    fn detects_tag_mismatch() {
+        let xml = "<root><a>1</b></root>";
+        match parse_err(xml) {
+            ParseError::TagMismatch { opening, ending } => {
+                assert_eq!(opening, "a");
+                assert_eq!(ending, "b");
+            }
+            _ => panic!("expected TagMismatch error"),
+        }
+    }
+
+    #[test]
+    fn detects_unexpected_structure() {
+        let xml = "<root><a></root>";
+        match parse_err(xml) {
+            ParseError::SyntaxError { .. } => {}
+            _ => panic!("expected SyntaxError"),
+        }
+    }
+    
+    #[test]
+    fn detects_file_error() {
+        let result = XmlNode::from_path("nonexistent.xml");
+        match result {
+            Err(ParseError::IoError(_)) => {}
+            _ => panic!("expected IoError for missing file"),
+        }
+    }
+    #[test]
+    fn parses_comment() {
+        let xml = "<root><!-- this is a comment --></root>";
+        let node = parse_ok(xml);
+
+        assert_eq!(node.name, "root");
+        assert_eq!(node.children[0].content, "<!-- this is a comment -->");
+        assert_eq!(node.content, "");
+    }
+
+    #[test]
+    fn parses_declaration() {
+        let xml = r#"<?xml ?><root></root>"#;
+        let node = parse_ok(xml);
+
+        assert_eq!(node.name, "root");
+        assert!(node.children.is_empty());
+    }
+
+    #[test]
+    fn parses_empty_element_tag() {
+        let xml = "<root><empty /></root>";
+        let node = parse_ok(xml);
+
+        assert_eq!(node.name, "root");
+        assert_eq!(node.children.len(), 1);
+        assert_eq!(node.children[0].name, "empty");
+        assert!(node.children[0].children.is_empty());
+        assert_eq!(node.children[0].content, "");
+    }
+
+    #[test]
+    fn parses_element_with_attribute() {
+        let xml = r#"<root><a attr="value"></a></root>"#;
+        let node = parse_ok(xml);
+
+        assert_eq!(node.name, "root");
+        assert_eq!(node.children[0].attributes[0].0, "attr");
+        assert_eq!(node.content, "");
+    }
+
+    #[test]
+    fn parses_element_with_attributes() {
+        let xml = r#"<root><a attr="value" id="2"></a></root>"#;
+        let node = parse_ok(xml);
+
+        assert_eq!(node.name, "root");
+        assert_eq!(node.children[0].attributes[0].0, "attr");
+        assert_eq!(node.children[0].attributes[1].1, "2");
+        assert_eq!(node.content, "");
+    }
+
+    #[test]
+    fn parses_cdata_xml() {
+        let xml = "<![CDATA[Some <encoded> & text]]>";
+        let node = parse_ok(xml);
+        assert_eq!(node.name, "#cdata");
+        assert_eq!(node.content, "<![CDATA[Some <encoded> & text]]>");
+        assert!(node.attributes.is_empty());
+        assert!(node.children.is_empty());
+    }
+
+    #[test]
+    fn parses_cdata_inside_xml() {
+        let xml = "<root><![CDATA[5 < 10 && x > 3]]></root>";
+        let node = parse_ok(xml);
+        assert_eq!(node.name, "root");
+        assert!(node.attributes.is_empty());
+        assert_eq!(node.children.len(), 1);
+
+        let cdata = &node.children[0];
+        assert_eq!(cdata.name, "#cdata");
+        assert_eq!(cdata.content, "<![CDATA[5 < 10 && x > 3]]>");
+    }
+
+    #[test]
+    fn builds_id_index() {
+        let xml = r#"<root><a id="x">1</a><b><c id="y">2</c></b><d id="x">3</d></root>"#;
+        let node = parse_ok(xml);
+        let (index, duplicates) = node.build_id_index_with_duplicates();
+
+        assert_eq!(index.len(), 2);
+        assert_eq!(index["x"].content, "1");
+        assert_eq!(index["y"].name, "c");
+        assert_eq!(duplicates, vec!["x"]);
+    }
+
+    #[test]
+    fn normalizes_multiline_attribute_value() {
+        let xml = "<root><a title=\"first\n\tsecond\r\nthird\"></a></root>";
+        let mut node = parse_ok(xml);
+        assert_eq!(node.children[0].attributes[0].1, "first\n\tsecond\r\nthird");
+
+        node.normalize_attribute_values();
+        assert_eq!(node.children[0].attributes[0].1, "first  second third");
+    }
+
+    #[test]
+    fn normalizes_attribute_values_with_option() {
+        let xml = "<root a=\"1\n2\"><b c=\"3\t4\" /></root>";
+        let opts = ParseOptions { normalize_attribute_values: true, ..ParseOptions::default() };
+
+        let raw = parse_ok(xml);
+        let node = parse_xml_with(xml, &opts).expect("expected valid XML");
+
+        assert_eq!(raw.attributes[0].1, "1\n2");
+        assert_eq!(node.attributes[0].1, "1 2");
+        assert_eq!(node.children[0].attributes[0].1, "3 4");
+    }
+
+    #[test]
+    fn converts_config_to_map() {
+        let xml = "<config><host>x</host><port>8080</port><port>9090</port>\
+                   <!-- c --><db><user>a</user></db></config>";
+        let map = parse_ok(xml).to_map();
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["host"], "x");
+        assert_eq!(map["port"], "9090");
+        assert!(!map.contains_key("db"));
+    }
+
+    #[test]
+    fn inherits_xml_lang() {
+        let xml = r#"<doc xml:lang="en"><p>hi</p><p xml:lang="de"><span>hallo</span></p><q xml:lang=""/></doc>"#;
+        let node = parse_ok(xml);
+
+        assert_eq!(node.lang().as_deref(), Some("en"));
+        assert_eq!(node.children[0].lang().as_deref(), Some("en"));
+        assert_eq!(node.children[1].lang().as_deref(), Some("de"));
+        assert_eq!(node.children[1].children[0].lang().as_deref(), Some("de"));
+        assert_eq!(node.children[2].lang(), None);
+        assert_eq!(parse_ok("<a>x</a>").lang(), None);
+
+        let nested = parse_ok(r#"<doc xml:lang="en"><p xml:lang="de"><s><em xml:lang="fr">x</em><b/></s></p></doc>"#);
+        let langs: Vec<(&str, Option<String>)> =
+            nested.descendants().map(|node| (node.name.as_str(), node.lang())).collect();
+        assert_eq!(
+            langs,
+            [
+                ("p", Some("de".to_string())),
+                ("s", Some("de".to_string())),
+                ("em", Some("fr".to_string())),
+                ("b", Some("de".to_string())),
+            ]
+        );
+        assert_eq!(nested.get_nodes("em")[0].lang().as_deref(), Some("fr"));
+    }
+
+    #[test]
+    fn builds_element_with_valid_name() {
+        let node = XmlNode::try_element("ns:item-1.x")
+            .expect("expected valid name")
+            .with_attribute("id", "1")
+            .with_child(XmlNode::element("_b").with_content("text"));
+
+        assert_eq!(node.to_xml_string(), r#"<ns:item-1.x id="1"><_b>text</_b></ns:item-1.x>"#);
+    }
+
+    #[test]
+    fn rejects_invalid_element_names() {
+        for name in ["1bad", "has space", "", "-x", "a<b"] {
+            match XmlNode::try_element(name) {
+                Err(ParseError::InvalidName { name: invalid }) => assert_eq!(invalid, name),
+                _ => panic!("expected InvalidName error for {:?}", name),
+            }
+        }
+    }
+
+    #[test]
+    fn gets_nodes_matching_any_tag() {
+        let xml = "<doc><h1>a</h1><p>x</p><h2>b</h2><sec><h1>c</h1><h2>d</h2></sec></doc>";
+        let node = parse_ok(xml);
+
+        let headings = node
+            .get_nodes_any(&["h1", "h2"])
+            .iter()
+            .map(|node| node.content.as_str())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(headings, vec!["a", "b", "c", "d"]);
+
+        let xml = r#"<doc xmlns:x="urn:x"><x:a>1</x:a><b>2</b><a>3</a><!--c--><y:a xmlns:y="urn:x">4</y:a></doc>"#;
+        let node = parse_ok(xml);
+        let names = |tags: &[&str]| {
+            node.get_nodes_any(tags).iter().map(|node| node.name.as_str()).collect::<Vec<&str>>()
+        };
+
+        assert_eq!(names(&["{urn:x}a", "b"]), ["x:a", "b", "y:a"]);
+        assert_eq!(names(&["*", "b"]), ["doc", "x:a", "b", "a", "y:a"]);
+    }
+
+    struct ByteReader<'a>(&'a [u8]);
+
+    impl Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((byte, rest)) if !buf.is_empty() => {
+                    buf[0] = *byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_concatenated_documents() {
+        let input = "<a>1</a>\n<?xml ?><b id=\"ü\"/>  \n<c><d>3</d></c>\n\n";
+
+        for results in [
+            parse_stream(input.as_bytes()).collect::<Vec<_>>(),
+            parse_stream(ByteReader(input.as_bytes())).collect::<Vec<_>>(),
+        ] {
+            let names = results
+                .into_iter()
+                .map(|r| r.expect("expected valid document").name)
+                .collect::<Vec<String>>();
+            assert_eq!(names, vec!["a", "b", "c"]);
+        }
+    }
+
+    #[test]
+    fn stream_skips_comments_between_documents() {
+        let input = "<!-- log --><a/>\n<!-- sep --><?xml version=\"1.0\"?><!-- b --><b/><?pi x?>\n<!-- end -->\n";
+
+        for results in [
+            parse_stream(input.as_bytes()).collect::<Vec<_>>(),
+            parse_stream(ByteReader(input.as_bytes())).collect::<Vec<_>>(),
+        ] {
+            let names = results
+                .into_iter()
+                .map(|r| r.expect("expected valid document").name)
+                .collect::<Vec<String>>();
+            assert_eq!(names, vec!["a", "b"]);
+        }
+        assert_eq!(parse_stream("  \n".as_bytes()).count(), 0);
+    }
+
+    #[test]
+    fn stream_reports_malformed_document_without_reading_on() {
+        let mut padding = io::repeat(b' ').take(1 << 20);
+        let mut stream = parse_stream("<a><b></a><c/>".as_bytes().chain(&mut padding));
+        assert!(matches!(stream.next(), Some(Err(ParseError::SyntaxError { .. }))));
+        assert!(stream.next().is_none());
+        drop(stream);
+        assert_eq!(padding.limit(), 1 << 20);
+
+        let mut stream = parse_stream("oops<a/><b/>".as_bytes());
+        assert!(matches!(stream.next(), Some(Err(ParseError::SyntaxError { .. }))));
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn stream_applies_document_checks_and_options() {
+        let results = parse_stream("<a/><b>\u{1}</b>".as_bytes()).collect::<Vec<_>>();
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(ParseError::InvalidCharacter { code: 1, .. })));
+
+        let results = parse_stream("<a/> <!-- c --> <?xml version=\"1.0\"?>".as_bytes()).collect::<Vec<_>>();
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+
+        let opts = ParseOptions { reject_duplicate_attributes: true, ..ParseOptions::default() };
+        let mut stream = parse_stream_with(r#"<a x="1"/><b x="1" x="2"/>"#.as_bytes(), &opts);
+        assert!(stream.next().is_some_and(|r| r.is_ok()));
+        assert!(matches!(stream.next(), Some(Err(ParseError::DuplicateAttribute { .. }))));
+    }
+
+    #[test]
+    fn stream_stops_on_trailing_garbage() {
+        let mut stream = parse_stream("<a/><b>".as_bytes());
+
+        assert!(stream.next().is_some_and(|r| r.is_ok()));
+        assert!(matches!(stream.next(), Some(Err(ParseError::SyntaxError { .. }))));
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn reports_duplicate_attribute_position() {
+        let xml = "<root>\n  <a id=\"1\" x=\"2\"\n     id=\"3\"/>\n</root>";
+        let parser = Parser::builder().reject_duplicate_attributes(true).build();
+
+        match parser.parse(xml) {
+            Err(ParseError::DuplicateAttribute { name, line, column }) => {
+                assert_eq!(name, "id");
+                assert_eq!((line, column), (3, 6));
+            }
+            _ => panic!("expected DuplicateAttribute error"),
+        }
+        assert_eq!(parse_ok(xml).children[0].attributes.len(), 3);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn round_trips_through_json() {
+        let xml = r#"<root b="2" a="1"><item>x</item><!-- c --><empty /></root>"#;
+        let node = parse_ok(xml);
+
+        let json = node.to_json();
+        let imported = XmlNode::from_json(&json).expect("expected valid JSON tree");
+        assert_eq!(imported.to_xml_string(), node.to_xml_string());
+
+        let minimal = XmlNode::from_json(r#"{"name":"a","children":[{"name":"b"}]}"#)
+            .expect("expected valid JSON tree");
+        assert_eq!(minimal.to_xml_string(), "<a><b></b></a>");
+
+        for invalid in [r#"{"content":"x"}"#, r#"{"name":"a","attributes":{"k":"v"}}"#, "[]"] {
+            assert!(matches!(XmlNode::from_json(invalid), Err(ParseError::InvalidJson { .. })));
+        }
+    }
+
+    #[test]
+    fn separates_content_runs_with_option() {
+        let xml = "<p>a<b/>b<!-- c -->c</p>";
+        let parser = Parser::builder().separate_content_runs(true).build();
+
+        assert_eq!(parse_ok(xml).content, "abc");
+        assert_eq!(parser.parse(xml).expect("expected valid XML").content, "a b c");
+        assert_eq!(parser.parse("<p><b/>a</p>").expect("expected valid XML").content, "a");
+    }
+
+    #[test]
+    fn observes_completed_elements_with_ancestors() {
+        let data = fs::read_to_string("tests/samples/5names.xml").expect("expected sample file");
+        let mut paths = Vec::new();
+        let node = parse_with_observer(&data, &mut |ancestors: &[&str], node: &XmlNode| {
+            if node.name == "name" {
+                paths.push(format!("/{}/{}", ancestors.join("/"), node.name));
+            }
+        })
+        .expect("expected valid XML");
+
+        assert_eq!(paths.len(), node.get_nodes("name").len());
+        assert!(paths.iter().all(|path| path.starts_with(&format!("/{}/", node.name))));
+        assert_eq!(paths[0], "/annotation/object/name");
+    }
+
+    #[test]
+    fn collects_attribute_values() {
+        let xml = r#"<root id="r"><a id="1"><b id="2" href="x"/></a><c href="y"><d id="3"/></c></root>"#;
+        let node = parse_ok(xml);
+
+        assert_eq!(node.collect_attribute("id"), vec!["r", "1", "2", "3"]);
+        assert_eq!(node.children[1].collect_attribute("href"), vec!["y"]);
+        assert!(node.collect_attribute("missing").is_empty());
+    }
+
+    #[test]
+    fn checks_leaf_and_empty_predicates() {
+        let xml = r#"<root><a>text</a><b><!-- c --></b><c x="1"/><d><e/></d></root>"#;
+        let node = parse_ok(xml);
+        let (a, b, c, d) = (&node.children[0], &node.children[1], &node.children[2], &node.children[3]);
+
+        assert!(node.has_children() && !node.is_leaf() && !node.is_empty_element());
+        assert!(a.is_leaf() && !a.has_children() && !a.is_empty_element());
+        assert!(b.is_leaf() && !b.has_children() && !b.is_empty_element());
+        assert!(c.is_leaf() && c.is_empty_element());
+        assert!(d.has_children() && !d.is_leaf());
+        assert!(!b.children[0].is_element());
+    }
+
+    #[test]
+    fn groups_repeated_children() {
+        let xml = "<item><name>a</name><!-- c --><id>1</id><name>b</name></item>";
+        let node = parse_ok(xml);
+        let groups = node.group_children();
+
+        assert_eq!(groups.len(), 2);
+        let names = groups["name"].iter().map(|n| n.content.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(groups["id"].len(), 1);
+        assert!(!groups.contains_key("#comment"));
+    }
+
+    #[test]
+    fn canonicalizes_independent_of_attribute_order() {
+        let first = parse_ok(r#"<root b="2" a="1"><x/><!-- c --><![CDATA[1 < 2]]></root>"#);
+        let second = parse_ok("<root a=\"1\"\n      b=\"2\">\n  <x></x>\n<![CDATA[1 < 2]]></root>");
+
+        assert_eq!(first.canonicalize(), r#"<root a="1" b="2"><x></x>1 &lt; 2</root>"#);
+        assert_eq!(first.canonicalize(), second.canonicalize());
+    }
+
+    #[test]
+    fn preserves_source_attribute_order() {
+        let xml = r#"<a zeta="1" alpha="2" mid="3" beta="4"></a>"#;
+        let node = parse_ok(xml);
+
+        let keys = node.attributes.iter().map(|(k, _)| k.as_str()).collect::<Vec<&str>>();
+        assert_eq!(keys, vec!["zeta", "alpha", "mid", "beta"]);
+        assert_eq!(node.to_xml_string(), xml);
+        assert_eq!(
+            node.attributes_sorted(),
+            vec![("alpha", "2"), ("beta", "4"), ("mid", "3"), ("zeta", "1")]
+        );
+    }
+
+    #[test]
+    fn reports_expected_rules_on_syntax_error() {
+        match parse_err("<root><a>1</a>") {
+            ParseError::SyntaxError { expected, .. } => {
+                assert!(!expected.is_empty());
+                assert!(expected.iter().any(|rule| rule == "closing_tag"));
+            }
+            _ => panic!("expected SyntaxError"),
+        }
+
+        let message = parse_err("<root><").to_string();
+        assert!(message.contains("expected one of:"), "{}", message);
+        assert!(!message.contains("unexpected:"), "{}", message);
+    }
+
+    #[test]
+    fn reports_unexpected_rules_on_syntax_error() {
+        let input = "<root>";
+        let pest_error = pest::error::Error::new_from_pos(
+            pest::error::ErrorVariant::ParsingError {
+                positives: vec![Rule::closing_tag],
+                negatives: vec![Rule::comment, Rule::cdata],
+            },
+            pest::Position::from_start(input),
+        );
+        let err = ParseError::from(pest_error);
+
+        match &err {
+            ParseError::SyntaxError { expected, unexpected, .. } => {
+                assert_eq!(expected, &["closing_tag"]);
+                assert_eq!(unexpected, &["comment", "cdata"]);
+            }
+            other => panic!("expected SyntaxError, got {:?}", other),
+        }
+        assert_eq!(
+            err.to_string(),
+            "Unexpected structure or syntax error in XML, expected one of: closing_tag, unexpected: comment, cdata"
+        );
+        assert_ne!(err, syntax_error());
+        assert_ne!(
+            err,
+            ParseError::SyntaxError {
+                expected: vec!["closing_tag".to_string()],
+                unexpected: Vec::new(),
+                pest: None,
+            }
+        );
+    }
+
+    #[test]
+    fn resolves_computed_paths() {
+        let xml = "<root><items><item><name>a</name></item><!-- c --><item><name>b</name></item></items></root>";
+        let node = parse_ok(xml);
+        let target = &node.children[0].children[2].children[0];
+
+        let path = node.get_path(target).expect("expected node in tree");
+        assert_eq!(path, "/root/items/item[2]/name");
+        assert!(std::ptr::eq(node.resolve_path(&path).expect("expected path to resolve"), target));
+
+        for (path, found) in node.descendants_with_path() {
+            assert!(std::ptr::eq(node.resolve_path(&path).expect("expected path to resolve"), found));
+        }
+        assert_eq!(node.resolve_path("/root/items/item/name").map(|n| n.content.as_str()), Some("a"));
+        assert!(node.resolve_path("/root/items/item[3]").is_none());
+        assert!(node.resolve_path("/other").is_none());
+        assert!(node.resolve_path("/root/items/item[0]").is_none());
+    }
+
+    #[test]
+    fn allows_whitespace_around_attribute_equals() {
+        let node = parse_ok(r#"<a b = "1"   c="2" ></a>"#);
+        assert_eq!(node.attributes_sorted(), vec![("b", "1"), ("c", "2")]);
+
+        let node = parse_ok("<root>\n  <a\n    b=\"1\"\n    c\n    =\n    \"2\"\n  />\n</root>");
+        assert_eq!(node.children[0].get_attribute("b"), Some("1"));
+        assert_eq!(node.children[0].get_attribute("c"), Some("2"));
+    }
+
+    #[test]
+    fn parses_declaration_with_attributes() {
+        let xml = "<?xml version = \"1.0\"\n      encoding=\"UTF-8\" ?>\n<root/>";
+        assert_eq!(parse_ok(xml).name, "root");
+        assert_eq!(parse_ok(r#"<?xml version="1.0"?><root/>"#).name, "root");
+    }
+
+    #[test]
+    fn declaration_attributes_need_leading_whitespace() {
+        for xml in [
+            r#"<?xmlversion="1.0"?><r/>"#,
+            r#"<?xml version="1.0"encoding="UTF-8"?><r/>"#,
+            r#"<r/><?pi-target"data"?>"#,
+        ] {
+            assert!(matches!(parse_err(xml), ParseError::SyntaxError { .. }), "{}", xml);
+        }
+        assert_eq!(parse_ok("<?xml\tversion=\"1.0\"\n?><r/><?pi-target data?>").name, "r");
+    }
+
+    #[test]
+    fn parses_empty_attribute_value() {
+        let node = parse_ok(r#"<a attr="" b="x"/>"#);
+        assert_eq!(node.get_attribute("attr"), Some(""));
+        assert_eq!(node.get_attribute("b"), Some("x"));
+    }
+
+    #[test]
+    fn rejects_attribute_without_value() {
+        for xml in ["<a attr></a>", "<a attr=></a>", "<a attr/>", "<a b=\"1\" attr />"] {
+            assert!(matches!(parse_err(xml), ParseError::SyntaxError { .. }), "{}", xml);
+        }
+    }
+
+    #[test]
+    fn truncated_input_never_panics() {
+        let data = fs::read_to_string("tests/samples/everything.txt").expect("expected sample file");
+        let extra = r#"<?xml version="1.0"?><a x="1" y=""><b/><![CDATA[<c>]]><!-- d --></a>"#;
+
+        for source in [data.as_str(), extra] {
+            for end in 0..=source.len() {
+                if let Some(prefix) = source.get(..end) {
+                    let _ = parse_xml(prefix);
+                }
+                let _ = parse_stream(&source.as_bytes()[..end]).count();
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_double_hyphen_comments_in_strict_mode() {
+        let strict = Parser::builder().strict_comments(true).build();
+
+        for xml in ["<root>\n<!-- a -- b --></root>", "<root>\n<!-- a ---></root>"] {
+            assert_eq!(parse_ok(xml).children[0].name, "#comment");
+            match strict.parse(xml) {
+                Err(ParseError::InvalidComment { line, column }) => assert_eq!((line, column), (2, 1)),
+                _ => panic!("expected InvalidComment error"),
+            }
+        }
+        assert!(strict.parse("<root><!-- a - b --></root>").is_ok());
+    }
+
+    #[test]
+    fn serializes_markup_heavy_content_as_cdata() {
+        let code = "if a < b && c > d { <tag> }";
+        let mut node = parse_ok("<root><code/><short/></root>");
+        node.children[0].set_content(code);
+        node.children[1].set_content("x > 1");
+
+        let opts = SerializeOptions { cdata_threshold: Some(3), ..SerializeOptions::default() };
+        let xml = node.to_xml_string_with(&opts);
+        assert_eq!(
+            xml,
+            "<root><code><![CDATA[if a < b && c > d { <tag> }]]></code><short>x > 1</short></root>"
+        );
+
+        let reparsed = parse_ok(&xml);
+        let cdata = &reparsed.children[0].children[0];
+        assert_eq!(cdata.name, "#cdata");
+        assert_eq!(cdata.content, format!("<![CDATA[{}]]>", code));
+        assert_eq!(wrap_cdata("a]]>b"), "<![CDATA[a]]]]><![CDATA[>b]]>");
+    }
+
+    #[test]
+    fn gets_value_from_content_or_attribute() {
+        let text = parse_ok("<item><price>9.99</price></item>");
+        let attr = parse_ok(r#"<item><price value="4.50"/></item>"#);
+        let both = parse_ok(r#"<item><price value="1">2</price></item>"#);
+
+        assert_eq!(text.value_of("price", Some("value")), Some("9.99"));
+        assert_eq!(attr.value_of("price", Some("value")), Some("4.50"));
+        assert_eq!(attr.value_of("price", None), None);
+        assert_eq!(both.value_of("price", Some("value")), Some("2"));
+        assert_eq!(text.value_of("missing", Some("value")), None);
+    }
+
+    #[test]
+    fn selects_nodes_by_namespace() {
+        let xml = r#"<root xmlns:a="urn:feed" xmlns:b="urn:feed" xmlns:c="urn:other">
+            <a:entry>1</a:entry><b:entry>2</b:entry><c:entry>3</c:entry><entry>4</entry>
+            <wrap xmlns="urn:feed"><entry>5</entry><inner xmlns=""><entry>6</entry></inner></wrap>
+        </root>"#;
+        let node = parse_ok(xml);
+
+        let found = node
+            .get_nodes_ns("urn:feed", "entry")
+            .iter()
+            .map(|n| n.content.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(found, vec!["1", "2", "5"]);
+        let clark = node.get_nodes("{urn:feed}entry");
+        assert_eq!(clark.iter().map(|n| n.content.as_str()).collect::<Vec<_>>(), found);
+        assert_eq!(node.get_nodes("{urn:other}entry")[0].content, "3");
+        assert_eq!(node.children[0].local_name(), "entry");
+        assert_eq!(node.children[0].prefix(), Some("a"));
+    }
+
+    #[test]
+    fn previews_tree_up_to_depth() {
+        let node = parse_ok("<root><a><b><c>deep</c></b></a><d>x</d></root>");
+
+        let preview = node.display_preview(1);
+        assert!(preview.contains("<a>") && preview.contains("<d>"));
+        assert!(preview.contains("… (1 more children)"));
+        assert!(!preview.contains("<b>") && !preview.contains("deep"));
+
+        assert!(node.display_preview(0).contains("… (2 more children)"));
+        assert_eq!(node.display_preview(10), node.to_string());
+    }
+
+    #[test]
+    fn accepts_leading_declaration() {
+        assert_eq!(parse_ok(r#"<?xml version="1.0"?><root/>"#).name, "root");
+        assert_eq!(parse_ok("\u{FEFF}<?xml version=\"1.0\"?>\n<root/>").name, "root");
+        assert_eq!(parse_ok("\u{FEFF}<root/>").name, "root");
+    }
+
+    #[test]
+    fn rejects_misplaced_declaration() {
+        for (xml, expected_line) in [
+            ("<root>\n<?xml version=\"1.0\"?>\n</root>", 2),
+            ("<root><a/></root>\n\n<?xml ?>", 3),
+            ("\n  <?xml version=\"1.0\"?><root/>", 2),
+        ] {
+            match parse_err(xml) {
+                ParseError::MisplacedDeclaration { line } => assert_eq!(line, expected_line, "{}", xml),
+                other => panic!("expected MisplacedDeclaration for {:?}, got {:?}", xml, other),
+            }
+        }
+        assert!(matches!(parse_err("<root><?xmlish?></root>"), ParseError::SyntaxError { .. }));
+    }
+
+    #[test]
+    fn syntax_errors_after_declaration_are_not_misplaced_declarations() {
+        for xml in [
+            "<?xml version=\"1.0\"?>",
+            "<?xml version=\"1.0\"?>oops<r/>",
+            "\u{FEFF}<?xml version=\"1.0\"?>\n",
+            "<?xml version=\"1.0\"?><r>",
+        ] {
+            assert!(matches!(parse_err(xml), ParseError::SyntaxError { .. }), "{:?}", xml);
+        }
+    }
+
+    #[test]
+    fn explains_missing_contents() {
+        let node = parse_ok("<root><a></a><b/><a>text</a></root>");
+
+        assert_eq!(node.try_get_contents_of("a"), Ok("text"));
+        assert_eq!(node.try_get_contents_of("b"), Err(ContentError::Empty { tag: "b".to_string() }));
+        assert_eq!(node.try_get_contents_of("c"), Err(ContentError::NotFound { tag: "c".to_string() }));
+    }
+
+    #[test]
+    fn comments_around_root_are_kept() {
+        let xml = "\n<!-- before -->\n<?xml-stylesheet href=\"a.xsl\"?>\n<root/>\n<!-- after -->\n";
+        let document = parse_document(xml).unwrap();
+
+        assert_eq!(document.root.name, "root");
+        let prolog: Vec<_> = document.prolog.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(prolog, ["#comment", "#pi"]);
+        assert_eq!(document.prolog[1].content, "<?xml-stylesheet href=\"a.xsl\"?>");
+        assert_eq!(document.epilog.len(), 1);
+        assert_eq!(document.epilog[0].content, "<!-- after -->");
+
+        assert_eq!(parse_ok(xml).name, "root");
+    }
+
+    #[test]
+    fn declaration_still_precedes_prolog() {
+        let document = parse_document("<?xml version=\"1.0\"?><!-- c --><root/>").unwrap();
+        assert_eq!(document.prolog.len(), 1);
+
+        let err = parse_err("<!-- c --><?xml version=\"1.0\"?><root/>");
+        assert!(matches!(err, ParseError::MisplacedDeclaration { line: 1 }));
+        assert!(parse_xml("<!-- only a comment -->").is_err());
+        assert!(parse_xml("<root/><other/>").is_err());
+    }
+
+    #[test]
+    fn flatten_lists_attributes_before_content() {
+        let root = parse_ok(r#"<root v="1"><item id="a">x</item><item id="b"/><!-- c --></root>"#);
+        let expected = [
+            ("/root/@v", "1"),
+            ("/root/item[1]/@id", "a"),
+            ("/root/item[1]", "x"),
+            ("/root/item[2]/@id", "b"),
+            ("/root/item[2]", ""),
+        ]
+        .map(|(p, v)| (p.to_string(), v.to_string()));
+        assert_eq!(root.flatten(), expected);
+    }
+
+    #[test]
+    fn errors_compare_and_clone() {
+        let err = parse_err("<a></b>");
+        assert_eq!(
+            err,
+            ParseError::TagMismatch { opening: "a".to_string(), ending: "b".to_string() }
+        );
+        assert_eq!(err.clone(), err);
+        assert_ne!(err, ParseError::MisplacedDeclaration { line: 1 });
+
+        let missing = XmlNode::from_path("no/such/file.xml").unwrap_err();
+        let other = ParseError::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        assert_eq!(missing, other);
+        assert_ne!(other, ParseError::from(io::Error::from(io::ErrorKind::PermissionDenied)));
+    }
+
+    #[test]
+    fn undeclared_prefix_is_rejected_when_required() {
+        let xml = r#"<soap:Envelope><soap:Body/></soap:Envelope>"#;
+        assert!(parse_xml(xml).is_ok());
+
+        let opts = ParseOptions { require_declared_namespaces: true, ..ParseOptions::default() };
+        assert_eq!(
+            parse_xml_with(xml, &opts).unwrap_err(),
+            ParseError::UndeclaredPrefix {
+                prefix: "soap".to_string(),
+                element: "soap:Envelope".to_string(),
+            }
+        );
+
+        let declared = r#"<s:a xmlns:s="urn:s" xml:lang="en"><s:b t:x="1" xmlns:t="urn:t"/></s:a>"#;
+        assert!(parse_xml_with(declared, &opts).is_ok());
+
+        // declarations go out of scope with their element
+        let sibling = r#"<r><a xmlns:p="urn:p"><p:x/></a><p:y/></r>"#;
+        let err = parse_xml_with(sibling, &opts).unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::UndeclaredPrefix { prefix: "p".to_string(), element: "p:y".to_string() }
+        );
+    }
+
+    #[test]
+    fn for_each_mut_renames_every_element() {
+        let mut root = parse_ok("<root><item>a</item><!-- c --><list><item/></list></root>");
+        let mut visited = 0;
+        root.for_each_mut(&mut |node| {
+            visited += 1;
+            if node.is_element() {
+                node.name = node.name.to_uppercase();
+            }
+        });
+
+        assert_eq!(visited, 5);
+        assert_eq!(
+            root.to_xml_string(),
+            "<ROOT><ITEM>a</ITEM><!-- c --><LIST><ITEM></ITEM></LIST></ROOT>"
+        );
+    }
+
+    #[test]
+    fn get_nodes_limited_stops_early() {
+        let items = "<item>x</item>".repeat(100);
+        let root = parse_ok(&format!("<root>{}</root>", items));
+
+        let first = root.get_nodes_limited("item", 3);
+        assert_eq!(first.len(), 3);
+        assert!(first.iter().all(|node| node.name == "item"));
+        assert_eq!(root.get_nodes_limited("item", 500).len(), 100);
+        assert!(root.get_nodes_limited("item", 0).is_empty());
+        assert_eq!(root.get_nodes_limited("*", 2)[1].name, "item");
+
+        // the root and the first three items
+        assert_eq!(visits(|| drop(root.get_nodes_limited("item", 3))), 4);
+    }
+
+    #[test]
+    fn io_error_display_is_platform_independent() {
+        let err = XmlNode::from_path(std::path::PathBuf::from("no/such/file.xml")).unwrap_err();
+        assert_eq!(err.to_string(), "File I/O error: entity not found");
+    }
+
+    #[test]
+    fn pretty_serializer_uses_indent() {
+        let root = parse_ok(r#"<root a="1"><item>x</item><list><!-- c --><e/></list></root>"#);
+
+        let spaces = root.to_xml_string_pretty(&PrettyOptions::default());
+        assert_eq!(
+            spaces,
+            "<root a=\"1\">\n  <item>x</item>\n  <list>\n    <!-- c -->\n    <e></e>\n  </list>\n</root>\n"
+        );
+
+        let opts = PrettyOptions { indent: Indent::Tabs, ..PrettyOptions::default() };
+        let tabs = root.to_xml_string_pretty(&opts);
+        assert!(tabs.contains("\n\t\t<e></e>\n"));
+        assert!(parse_ok(&tabs).structurally_equal(&root));
+    }
+
+    #[test]
+    fn indent_is_detected_from_source() {
+        assert_eq!(Indent::detect("<a>\n\t<b/>\n\t\t<c/>\n</a>"), Some(Indent::Tabs));
+        assert_eq!(Indent::detect("<a>\n    <b>\n        <c/>\n</b></a>"), Some(Indent::Spaces(4)));
+        assert_eq!(Indent::detect("<a><b/></a>"), None);
+    }
+
+    #[test]
+    fn base64_content_is_decoded() {
+        let root = parse_ok("<data>\n  SGVsbG8s\n  IHdvcmxkIQ==\n</data>");
+        assert_eq!(root.content_trimmed(), root.content.trim());
+        assert_eq!(root.decode_base64_content().unwrap(), b"Hello, world!");
+
+        let bytes = parse_ok("<b>AP8Q</b>").decode_base64_content().unwrap();
+        assert_eq!(bytes, [0x00, 0xff, 0x10]);
+        assert_eq!(parse_ok("<b></b>").decode_base64_content().unwrap(), b"");
+
+        assert_eq!(
+            parse_ok("<b>AB*D</b>").decode_base64_content(),
+            Err(Base64Error::InvalidCharacter { ch: '*', position: 2 })
+        );
+        assert_eq!(
+            parse_ok("<b>ABC</b>").decode_base64_content(),
+            Err(Base64Error::InvalidLength { symbols: 3 })
+        );
+        assert!(parse_ok("<b>AB=C</b>").decode_base64_content().is_err());
+    }
+
+    #[test]
+    fn matches_name_and_attribute_selectors() {
+        let root = parse_ok(
+            r#"<root><item active="true">a</item><item active="false"/><item/><!-- item --></root>"#,
+        );
+        let item = &root.children[0];
+
+        assert!(item.matches("item"));
+        assert!(item.matches("*"));
+        assert!(!item.matches("root"));
+        assert!(item.matches("item[@active]"));
+        assert!(item.matches("item[@active='true']"));
+        assert!(item.matches(r#"*[@active="true"]"#));
+        assert!(!root.children[1].matches("item[@active='true']"));
+        assert!(!root.children[3].matches("*"));
+        assert!(!item.matches("item[active]"));
+        assert!(!item.matches("item[@active='true'"));
+
+        let active = root.descendants().filter(|n| n.matches("item[@active='true']")).count();
+        assert_eq!(active, 1);
+        assert_eq!(root.descendants().filter(|n| n.matches("item[@active]")).count(), 2);
+        assert_eq!(root.descendants().count(), 4);
+    }
+
+    #[test]
+    fn select_supports_presence_equality_and_negation() {
+        let root = parse_ok(
+            r#"<menu><item id="1" disabled="true"/><item id="2" disabled="false"/><item id="3"/><group><item id="4" disabled=""/></group></menu>"#,
+        );
+        let ids = |selector: &str| {
+            root.select(selector).iter().filter_map(|n| n.get_attribute("id")).collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids("//item[@disabled]"), vec!["1", "2", "4"]);
+        assert_eq!(ids("//item[@disabled='true']"), vec!["1"]);
+        assert_eq!(ids("//item[not(@disabled)]"), vec!["3"]);
+        assert_eq!(ids("//item[not(@disabled='true')]"), vec!["2", "3", "4"]);
+        assert_eq!(ids("item[@disabled][not(@id='2')]"), vec!["1", "4"]);
+        assert_eq!(root.select("//*[not(@id)]").len(), 2);
+        assert!(root.children[2].matches("item[not( @disabled )]"));
+
+        assert!(root.select("//item[not(@disabled]").is_empty());
+        assert!(root.select("//item[not(disabled)]").is_empty());
+    }
+
+    #[test]
+    fn own_content_ignores_descendants() {
+        let root = parse_ok("<root><name>outer<name>inner</name></name><empty><name>deep</name></empty></root>");
+
+        assert_eq!(root.children[0].own_content(), "outer");
+        assert_eq!(root.children[1].own_content(), "");
+        assert_eq!(root.children[1].get_contents_of("name"), Some("deep"));
+    }
+
+    #[test]
+    fn collapse_empty_controls_empty_elements() {
+        let root = parse_ok(r#"<root><a></a><b x="1"/><c> </c><d><!-- c --></d></root>"#);
+
+        assert_eq!(
+            root.to_xml_string(),
+            r#"<root><a></a><b x="1"></b><c></c><d><!-- c --></d></root>"#
+        );
+        let opts = SerializeOptions { collapse_empty: true, ..SerializeOptions::default() };
+        assert_eq!(
+            root.to_xml_string_with(&opts),
+            r#"<root><a/><b x="1"/><c/><d><!-- c --></d></root>"#
+        );
+    }
+
+    #[test]
+    fn write_children_named_splits_records() {
+        let root = parse_ok(r#"<records><record id="1">a</record><meta/><record id="2"><x/></record></records>"#);
+        let dir = std::env::temp_dir().join(format!("xml_parser_split_{}", std::process::id()));
+
+        assert_eq!(root.write_children_named("record", &dir).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.join("record_2.xml")).unwrap(),
+            r#"<record id="2"><x></x></record>"#
+        );
+        assert!(!dir.join("record_3.xml").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn converts_from_strings() {
+        let borrowed = XmlNode::try_from("<root><a>1</a></root>").unwrap();
+        let owned = XmlNode::try_from(String::from("<root><a>1</a></root>")).unwrap();
+        let parsed: XmlNode = "<root><a>1</a></root>".parse().unwrap();
+
+        assert!(borrowed.structurally_equal(&owned));
+        assert!(borrowed.structurally_equal(&parsed));
+        assert_eq!(
+            XmlNode::try_from(String::from("<a></b>")).unwrap_err(),
+            ParseError::TagMismatch { opening: "a".to_string(), ending: "b".to_string() }
+        );
+    }
+
+    #[test]
+    fn sort_attributes_only_affects_output() {
+        let root = parse_ok(r#"<root b="2" a="1" b="3"><item z="" y=""/></root>"#);
+
+        let opts = SerializeOptions { sort_attributes: true, ..SerializeOptions::default() };
+        assert_eq!(
+            root.to_xml_string_with(&opts),
+            r#"<root a="1" b="2"><item y="" z=""></item></root>"#
+        );
+        let pretty = PrettyOptions { sort_attributes: true, ..PrettyOptions::default() };
+        assert!(
+            root.to_xml_string_pretty(&pretty)
+                .starts_with("<root a=\"1\" b=\"2\">\n  <item y=\"\" z=\"\">")
+        );
+
+        let names = root.attributes.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["b", "a", "b"]);
+    }
+
+    #[test]
+    fn inner_text_includes_descendants() {
+        let root = parse_ok("<p>Hello,<b>bold</b><!-- note --><![CDATA[<raw>]]><i>x<u>y</u></i></p>");
+
+        assert_eq!(root.content, "Hello,");
+        assert_eq!(root.inner_text(), "Hello,bold<raw>xy");
+        assert_eq!(root.children[3].content, "x");
+        assert_eq!(root.children[3].inner_text(), "xy");
+        assert_eq!(parse_ok("<a/>").inner_text(), "");
+    }
+
+    #[test]
+    fn colored_display_wraps_names_in_ansi_codes() {
+        let root = parse_ok(r#"<root id="1"><!-- c --><a>text</a></root>"#);
+        let plain = root.display_with(&DisplayOptions::default());
+        assert_eq!(plain, root.to_string());
+        assert!(!plain.contains('\x1b'));
+
+        let colored = root.display_with(&DisplayOptions { color: true, ..DisplayOptions::default() });
+        assert!(colored.starts_with("\x1b[34m<root\x1b[0m \x1b[36mid\x1b[0m=\x1b[32m\"1\"\x1b[0m"));
+        assert!(colored.contains("\x1b[90m<!-- c -->\x1b[0m"));
+        assert!(colored.contains("  text\n"));
+    }
+
+    #[test]
+    fn get_attributes_returns_every_duplicate() {
+        let node = parse_ok(r#"<item class="a" id="1" class="b"/>"#);
+
+        assert_eq!(node.get_attributes("class"), ["a", "b"]);
+        assert_eq!(node.get_attribute("class"), Some("a"));
+        assert_eq!(node.get_attributes("id"), ["1"]);
+        assert!(node.get_attributes("missing").is_empty());
+    }
+
+    #[test]
+    fn assert_structure_reports_every_violation() {
+        let spec = StructureSpec::new()
+            .require_child("book", "title")
+            .require_attribute("book", "id")
+            .require_child("library", "book");
+
+        let valid = parse_ok(r#"<library><book id="1"><title>Dune</title></book></library>"#);
+        assert_eq!(valid.assert_structure(&spec), Ok(()));
+
+        let invalid = parse_ok(r#"<library><book><title>A</title></book><book id="2"/></library>"#);
+        let violations = invalid.assert_structure(&spec).unwrap_err();
+        assert_eq!(
+            violations,
+            [
+                Violation::MissingAttribute {
+                    path: "/library/book[1]".to_string(),
+                    attribute: "id".to_string(),
+                },
+                Violation::MissingChild {
+                    path: "/library/book[2]".to_string(),
+                    child: "title".to_string(),
+                },
+            ]
+        );
+        assert_eq!(violations[1].to_string(), "/library/book[2] is missing a <title> child");
+    }
+
+    #[test]
+    fn get_nodes_indexed_counts_same_named_siblings() {
+        let root = parse_ok("<root><name>a</name><id/><name>b</name><name>c</name><x><name>d</name></x></root>");
+        let indexed = root
+            .get_nodes_indexed("name")
+            .into_iter()
+            .map(|(index, node)| (index, node.content.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(indexed, [(1, "a"), (2, "b"), (3, "c"), (1, "d")]);
+        assert_eq!(root.get_nodes_indexed("root")[0].0, 1);
+    }
+
+    #[test]
+    fn character_rules_follow_declared_version() {
+        let invalid = |code, version: &str, line, column| ParseError::InvalidCharacter {
+            code,
+            version: version.to_string(),
+            line,
+            column,
+        };
+
+        // a referenced C0 control: only XML 1.1 allows it
+        assert_eq!(parse_err("<a>&#x1;</a>"), invalid(1, "1.0", 1, 4));
+        assert_eq!(
+            parse_err("<?xml version=\"1.0\"?>\n<a>&#1;</a>"),
+            invalid(1, "1.0", 2, 4)
+        );
+        assert!(parse_xml("<?xml version=\"1.1\"?><a>&#x1;</a>").is_ok());
+
+        // a literal C1 control: only XML 1.0 allows it
+        assert!(parse_xml("<a>\u{80}</a>").is_ok());
+        assert_eq!(
+            parse_err("<?xml version=\"1.1\"?><a>\u{80}</a>"),
+            invalid(0x80, "1.1", 1, 25)
+        );
+
+        // never allowed
+        assert!(parse_xml("<?xml version=\"1.1\"?><a>\u{1}</a>").is_err());
+        assert!(parse_xml("<?xml version=\"1.1\"?><a>&#0;</a>").is_err());
+        assert!(parse_xml("<a><![CDATA[&#1;]]><!-- &#1; --></a>").is_ok());
+        assert!(parse_xml("<?xml version=\"1.0\"?><?pi &#1;?><a/><?pi &#x1;?>").is_ok());
+    }
+
+    #[test]
+    fn prune_empty_removes_empty_elements() {
+        let mut root = parse_ok(
+            r#"<root><a/><b>text</b><c><d/><e></e></c><f id="1"/><g><!-- keep --></g></root>"#,
+        );
+
+        // a, d, e, then c once its children are gone
+        assert_eq!(root.prune_empty(), 4);
+        assert_eq!(
+            root.to_xml_string(),
+            r#"<root><b>text</b><f id="1"></f><g><!-- keep --></g></root>"#
+        );
+        assert_eq!(root.prune_empty(), 0);
     }
 
-    pub fn get_contents_of(&self, tag: &str) -> Option<&str> {
-        if self.name == tag && !self.content.is_empty() {
-            return Some(self.content.as_str());
-        }
+    #[test]
+    fn auto_close_recovers_from_unclosed_elements() {
+        let opts = ParseOptions { auto_close: true, ..ParseOptions::default() };
 
-        for child in &self.children {
-            if let Some(found) = child.get_contents_of(tag) {
-                return Some(found);
-            }
-        }
+        assert!(matches!(parse_err("<a><b></a>"), ParseError::SyntaxError { .. }));
+        let document = parse_document_with("<a><b>text</a>", &opts).unwrap();
+        assert_eq!(document.root.to_xml_string(), "<a><b>text</b></a>");
+        assert_eq!(
+            document.warnings,
+            [Warning {
+                kind: WarningKind::AutoClosed { name: "b".to_string() },
+                line: 1,
+                column: 11,
+            }]
+        );
+        assert_eq!(document.warnings[0].to_string(), "Auto-closed <b> at line 1, column 11");
 
-        None
+        let nested = parse_document_with("<r><p><i>x<br></p><p>y</p></r>", &opts).unwrap();
+        assert_eq!(nested.root.to_xml_string(), "<r><p><i>x<br></br></i></p><p>y</p></r>");
+        assert_eq!(nested.warnings.len(), 2);
+
+        let unclosed = parse_document_with("<!-- c --><a><b/>", &opts).unwrap();
+        assert_eq!(unclosed.prolog.len(), 1);
+        assert_eq!(unclosed.root.to_xml_string(), "<a><b></b></a>");
+
+        // a closing tag matching no open element is still an error
+        assert!(matches!(
+            parse_document_with("<a><b></c></a>", &opts),
+            Err(ParseError::TagMismatch { .. })
+        ));
+        assert!(parse_document_with("<a/><b/>", &opts).is_err());
+        assert!(parse_document_with("text", &opts).is_err());
     }
 
-    pub fn get_nodes(&self, tag: &str) -> Vec<&XmlNode> {
-        let mut results = Vec::new();
+    #[test]
+    fn writers_output_reparses_to_equal_tree() {
+        let root = parse_ok(r#"<root a="1"><item>x &amp; y</item><!-- c --><list><e/></list></root>"#);
 
-        if self.name == tag {
-            results.push(self);
-        }
+        let mut compact = Vec::new();
+        root.to_writer(&mut compact).unwrap();
+        let compact = String::from_utf8(compact).unwrap();
+        assert_eq!(compact, root.to_xml_string());
+        assert!(parse_ok(&compact).structurally_equal(&root));
 
-        for child in &self.children {
-            results.extend(child.get_nodes(tag));
-        }
-        results
+        let mut pretty = Vec::new();
+        root.to_writer_pretty(&mut pretty).unwrap();
+        let pretty = String::from_utf8(pretty).unwrap();
+        assert!(pretty.contains("\n  <item>x &amp; y</item>\n"));
+        assert!(parse_ok(&pretty).structurally_equal(&root));
     }
 
-    fn display_node(&self, f: &mut std::fmt::Formatter<'_>, indent: usize) -> std::fmt::Result {
-        let pad = "  ".repeat(indent);
+    #[test]
+    fn declaration_pseudo_attributes_are_kept() {
+        let xml = r#"<?xml version="1.0" encoding="ISO-8859-1" standalone="yes"?><root/>"#;
+        let document = parse_document(xml).unwrap();
 
-        match self.name.as_str() {
-            "#comment" => writeln!(f, "{}{}", pad, self.content),
-            "#cdata" => writeln!(f, "{}{}", pad, self.content),
-            _ => {
-                write!(f, "{}<{}", pad, self.name)?;
+        let encoding = document.declaration.iter().find(|(k, _)| k == "encoding");
+        assert_eq!(encoding.map(|(_, v)| v.as_str()), Some("ISO-8859-1"));
+        assert_eq!(document.declaration.len(), 3);
+        assert_eq!(document.declaration[0], ("version".to_string(), "1.0".to_string()));
 
-                for (k, v) in &self.attributes {
-                    write!(f, " {}=\"{}\"", k, v)?;
-                }
-                writeln!(f, ">")?;
+        assert!(parse_document("<root/>").unwrap().declaration.is_empty());
+        let opts = ParseOptions { auto_close: true, ..ParseOptions::default() };
+        assert_eq!(parse_document_with(xml, &opts).unwrap().declaration.len(), 3);
+    }
 
-                if !self.content.is_empty() {
-                    writeln!(f, "{}  {}", pad, self.content)?;
-                }
+    #[test]
+    fn text_extraction_skips_comments_by_default() {
+        let root = parse_ok("<root>Hello<!-- hidden --><b>world</b></root>");
 
-                for child in &self.children {
-                    child.display_node(f, indent + 3)?;
-                }
+        assert_eq!(root.inner_text(), "Helloworld");
+        assert_eq!(root.get_contents_of("#comment"), None);
+        assert_eq!(root.value_of("#comment", None), None);
+        assert!(matches!(root.try_get_contents_of("#comment"), Err(ContentError::NotFound { .. })));
+        assert_eq!(root.get_contents_of("b"), Some("world"));
 
-                writeln!(f, "{}</{}>", pad, self.name)
-            }
-        }
+        let opts = TextOptions { include_comments: true };
+        assert_eq!(root.inner_text_with(&opts), "Hello hidden world");
+        assert_eq!(root.get_contents_of_with("#comment", &opts), Some(" hidden "));
     }
 
-}
+    #[test]
+    fn content_is_decoded_and_escaped_on_output() {
+        let mut node = XmlNode::element("x");
+        node.set_content("a & b");
+        assert_eq!(node.to_xml_string(), "<x>a &amp; b</x>");
+        assert_eq!(parse_ok(&node.to_xml_string()).content, "a & b");
 
-#[derive(Debug, Error)]
-pub enum ParseError {
-    #[error("Tag mismatch: opening tag <{opening}>, ending tag </{ending}>")]
-    TagMismatch { opening: String, ending: String },
+        let root = parse_ok("<p>1 &lt; 2 &amp;&amp; 3 &gt; 2 &quot;&apos; &#65;&#x42;</p>");
+        assert_eq!(root.content, "1 < 2 && 3 > 2 \"' AB");
+        assert_eq!(root.to_xml_string(), "<p>1 &lt; 2 &amp;&amp; 3 > 2 \"' AB</p>");
+        assert_eq!(parse_ok("<p>a]]&gt;b</p>").to_xml_string(), "<p>a]]&gt;b</p>");
 
-    #[error("Unexpected structure or syntax error in XML")]
-    SyntaxError,
+        assert_eq!(
+            parse_err("<p>\n  x &nbsp; y</p>"),
+            ParseError::InvalidEntity { entity: "&nbsp;".to_string(), line: 2, column: 5 }
+        );
+        assert!(matches!(parse_err("<p>a & b</p>"), ParseError::InvalidEntity { .. }));
+    }
 
-    #[error("File I/O error: {0}")]
-    IoError(#[from] io::Error),
+    #[test]
+    fn nearest_prefers_shallow_matches() {
+        let root = parse_ok("<root><a><b><name>deep</name></b></a><name>shallow</name></root>");
 
-    #[error("Unexpected Internal Error: {message}\nIf you see this error, something went wrong :/")]
-    InternalError{message: String},
-}
+        assert_eq!(root.get_nodes("name")[0].content, "deep");
+        assert_eq!(root.nearest("name").map(|n| n.content.as_str()), Some("shallow"));
+        assert_eq!(root.nearest("root").map(|n| n.name.as_str()), Some("root"));
+        assert!(root.nearest("missing").is_none());
+    }
 
-pub fn parse_xml(input: &str) -> Result<XmlNode, ParseError> {
-    let mut parsed = Grammar::parse(Rule::xml, input)
-        .map_err(|_| ParseError::SyntaxError)?;
+    #[test]
+    fn decodes_attribute_values() {
+        let root = parse_ok(r#"<show title="Tom &amp; Jerry" mark="&#169;&#xA0;&quot;x&quot;"/>"#);
 
-    let root = parsed.next().ok_or(ParseError::SyntaxError)?;
+        assert_eq!(root.get_attribute("title"), Some("Tom & Jerry"));
+        assert_eq!(root.get_attribute("mark"), Some("\u{A9}\u{A0}\"x\""));
+        assert_eq!(
+            root.to_xml_string(),
+            "<show title=\"Tom &amp; Jerry\" mark=\"©\u{A0}&quot;x&quot;\"></show>"
+        );
+        assert_eq!(parse_ok(&root.to_xml_string()).attributes, root.attributes);
+    }
 
-    let start_element = root
-        .into_inner()
-        .find(|p| p.as_rule() == Rule::element)
-        .ok_or(ParseError::SyntaxError)?;
+    #[test]
+    fn rejects_malformed_attribute_references() {
+        assert_eq!(
+            parse_err("<a>\n<b title=\"x &#xZZ; y\"/></a>"),
+            ParseError::InvalidEntity { entity: "&#xZZ;".to_string(), line: 2, column: 13 }
+        );
+        assert!(matches!(parse_err(r#"<a t="fish & chips"/>"#), ParseError::InvalidEntity { .. }));
+    }
 
-    parse_element(start_element)
-}
+    #[test]
+    fn strict_attribute_values_reject_literal_less_than() {
+        let xml = r#"<a cmp="1 < 2"/>"#;
+        assert_eq!(parse_ok(xml).get_attribute("cmp"), Some("1 < 2"));
 
+        let strict = ParserBuilder::new().strict_attribute_values(true).build();
+        assert_eq!(
+            strict.parse(xml).unwrap_err(),
+            (ParseError::InvalidAttributeValue { name: "cmp".to_string(), line: 1, column: 11 })
+        );
+    }
 
-fn parse_element(element: pest::iterators::Pair<Rule>) -> Result<XmlNode, ParseError> {
-    let mut inner = element.into_inner();
-    let pair = inner.next().ok_or(ParseError::SyntaxError)?;
+    #[test]
+    fn normalizes_literal_whitespace_but_not_references() {
+        let opts = ParseOptions { normalize_attribute_values: true, ..ParseOptions::default() };
+        let root = parse_xml_with("<a v=\"x\ny&#10;z\"/>", &opts).unwrap();
 
-    match pair.as_rule() {
-        Rule::full_element => {
-            let mut inner = pair.into_inner();
-            let opening = inner.next().ok_or(ParseError::SyntaxError)?;
-            let (name_open, attrs) = parse_opening_tag(opening)?;
+        assert_eq!(root.get_attribute("v"), Some("x y\nz"));
+        assert_eq!(root.to_xml_string(), "<a v=\"x y&#10;z\"></a>");
+    }
 
-            let mut children = Vec::new();
-            let mut content = String::new();
+    #[test]
+    fn collects_warnings_for_lenient_parses() {
+        let xml = "<a x=\"1\" y=\"2\"\n   x=\"3\"><!-- a -- b --></a>";
+        let (root, warnings) = parse_xml_with_warnings(xml, &ParseOptions::default()).unwrap();
 
-            for item in inner {
-                match item.as_rule() {
-                    Rule::content => content.push_str(item.as_str().trim()),
-                    Rule::element => children.push(parse_element(item)?),
-                    Rule::closing_tag => {
-                        let name_close = item.into_inner().next().unwrap().as_str().to_string();
-                        if name_close != name_open {
-                            return Err(ParseError::TagMismatch {
-                                opening: name_open,
-                                ending: name_close,
-                            });
-                        }
-                        return Ok(XmlNode {
-                            name: name_open,
-                            attributes: attrs,
-                            content,
-                            children,
-                        });
-                    }
-                    _ => {}
-                }
-            }
-            Err(ParseError::SyntaxError)
-        }
+        assert_eq!(root.get_attributes("x"), vec!["1", "3"]);
+        assert_eq!(
+            warnings,
+            vec![
+                Warning {
+                    kind: WarningKind::DuplicateAttribute { name: "x".to_string() },
+                    line: 2,
+                    column: 4,
+                },
+                Warning { kind: WarningKind::InvalidComment, line: 2, column: 10 },
+            ]
+        );
+        assert_eq!(warnings[0].to_string(), "Duplicate attribute 'x' at line 2, column 4");
 
-        Rule::empty_element_tag => {
-            let mut inner = pair.into_inner();
-            let name = inner.next().unwrap().as_str().to_string();
-            let attrs = parse_attributes(inner);
-            Ok(XmlNode {
-                name,
-                attributes: attrs,
-                content: String::new(),
-                children: Vec::new(),
-            })
-        }
+        let (_, warnings) = parse_xml_with_warnings("<a x=\"1\"/>", &ParseOptions::default()).unwrap();
+        assert!(warnings.is_empty());
+    }
 
-        Rule::comment => Ok(XmlNode {
-            name: "#comment".to_string(),
-            attributes: Vec::new(),
-            content: pair.as_str().to_string(),
-            children: Vec::new(),
-        }),
+    #[test]
+    fn case_insensitive_lookup_keeps_original_names() {
+        let root = parse_ok("<List><Item>a</Item><item>b</item><ITEM/><items/></List>");
 
-        Rule::cdata => Ok(XmlNode {
-            name: "#cdata".to_string(),
-            attributes: Vec::new(),
-            content: pair.as_str().to_string(),
-            children: Vec::new(),
-        }),
+        let found = root.get_nodes_ci("item").iter().map(|n| n.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(found, vec!["Item", "item", "ITEM"]);
+        assert_eq!(root.get_nodes("item").len(), 1);
+        assert_eq!(root.get_nodes_ci("*").len(), 5);
+        assert_eq!(
+            root.to_xml_string(),
+            "<List><Item>a</Item><item>b</item><ITEM></ITEM><items></items></List>"
+        );
+    }
+
+    #[test]
+    fn removes_attribute_from_whole_tree() {
+        let mut root = parse_ok(
+            r#"<users password="root"><user name="a" password="x"><login password="y"/></user><user name="b"/></users>"#,
+        );
 
-        _ => Err(ParseError::InternalError {
-            message: format!("Unexpected rule: {:?}", pair.as_rule()),
-        }),
+        assert_eq!(root.remove_attribute_everywhere("password"), 3);
+        assert_eq!(
+            root.to_xml_string(),
+            r#"<users><user name="a"><login></login></user><user name="b"></user></users>"#
+        );
+        assert_eq!(root.remove_attribute_everywhere("password"), 0);
     }
-}
 
-fn parse_opening_tag(pair: pest::iterators::Pair<Rule>,
-) -> Result<(String, Vec<(String, String)>), ParseError> {
+    #[test]
+    fn emits_declaration_only_when_asked() {
+        let document = parse_document(r#"<?xml version="1.0" encoding="UTF-8"?><a>x</a>"#).unwrap();
+        let declaration = document.xml_declaration().unwrap();
+        assert_eq!(declaration.to_string(), r#"<?xml version="1.0" encoding="UTF-8"?>"#);
 
-    let mut inner = pair.into_inner().filter(|p| p.as_rule() != Rule::WHITESPACE);
-    let name = inner.next().ok_or(ParseError::SyntaxError)?.as_str().to_string();
-    let attrs = parse_attributes(inner);
-    Ok((name, attrs))
-}
+        assert_eq!(document.root.to_xml_string(), "<a>x</a>");
+        let opts = SerializeOptions { emit_declaration: Some(declaration), ..SerializeOptions::default() };
+        assert_eq!(
+            document.root.to_xml_string_with(&opts),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<a>x</a>"
+        );
 
-fn parse_attributes<'a>(
-    pairs: impl Iterator<Item = pest::iterators::Pair<'a, Rule>>,
-) -> Vec<(String, String)> {
-    let mut attributes = Vec::new();
-    for attr in pairs {
-        if attr.as_rule() == Rule::attribute {
-            let mut parts = attr.into_inner();
-            let key = parts.next().unwrap().as_str().to_string();
-            let value = parts.next().unwrap().as_str().trim_matches('"').to_string();
-            attributes.push((key, value));
-        }
+        let mut written = Vec::new();
+        let standalone = XmlDeclaration { standalone: Some(true), ..XmlDeclaration::default() };
+        let opts = SerializeOptions { emit_declaration: Some(standalone), ..SerializeOptions::default() };
+        document.root.to_writer_with(&mut written, &opts).unwrap();
+        assert_eq!(written, b"<?xml version=\"1.0\" standalone=\"yes\"?>\n<a>x</a>");
+
+        assert_eq!(parse_document("<a/>").unwrap().xml_declaration(), None);
     }
-    attributes
-}
 
+    #[test]
+    fn character_references_must_be_xml_characters() {
+        let root = parse_ok(r#"<a title="&#x1F600;">&#x1F600; &#128512;</a>"#);
+        assert_eq!(root.content, "\u{1F600} \u{1F600}");
+        assert_eq!(root.get_attribute("title"), Some("\u{1F600}"));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let invalid = |entity: &str, column| ParseError::InvalidEntity {
+            entity: entity.to_string(),
+            line: 1,
+            column,
+        };
+        assert_eq!(parse_err("<a>x &#x110000;</a>"), invalid("&#x110000;", 6));
+        assert_eq!(parse_err("<a>&#99999999999;</a>"), invalid("&#99999999999;", 4));
+        assert_eq!(parse_err("<a>&#0;</a>"), invalid("&#0;", 4));
+        assert_eq!(parse_err("<?xml version=\"1.1\"?><a>&#x0;</a>"), invalid("&#x0;", 25));
+        assert_eq!(parse_err("<a>&#xD800;</a>"), invalid("&#xD800;", 4));
+        assert_eq!(parse_err("<a>&#xFFFF;</a>"), invalid("&#xFFFF;", 4));
+        assert_eq!(parse_err(r#"<a v="&#x110000;"/>"#), invalid("&#x110000;", 7));
+    }
 
-    fn parse_ok(input: &str) -> XmlNode {
-        parse_xml(input).expect("expected valid XML")
+    #[test]
+    fn nth_child_counts_pseudo_nodes_but_nth_element_child_does_not() {
+        let root = parse_ok("<row><!-- id --><cell>1</cell><!-- name --><cell>Ann</cell></row>");
+
+        assert_eq!(root.nth_child(0).map(|n| n.name.as_str()), Some("#comment"));
+        assert_eq!(root.nth_child(1).map(|n| n.content.as_str()), Some("1"));
+        assert_eq!(root.nth_child(3).map(|n| n.content.as_str()), Some("Ann"));
+        assert!(root.nth_child(4).is_none());
+
+        assert_eq!(root.nth_element_child(0).map(|n| n.content.as_str()), Some("1"));
+        assert_eq!(root.nth_element_child(1).map(|n| n.content.as_str()), Some("Ann"));
+        assert!(root.nth_element_child(2).is_none());
     }
 
-    fn parse_err(input: &str) -> ParseError {
-        parse_xml(input).unwrap_err()
+    #[test]
+    fn prunes_whitespace_only_text_kept_by_preserve_whitespace() {
+        let opts = ParseOptions { preserve_whitespace: true, ..ParseOptions::default() };
+        let mut root = parse_xml_with("<list>\n  <item> a b </item>\n  <item>\t</item>\n</list>", &opts).unwrap();
+
+        assert!(root.content_is_whitespace());
+        assert_eq!(root.content, "\n  \n  \n");
+        assert!(!root.children[0].content_is_whitespace());
+        assert!(root.children[1].content_is_whitespace());
+
+        root.prune_whitespace_only_text();
+        assert_eq!(root.content, "");
+        assert_eq!(root.children[0].content, " a b ");
+        assert_eq!(root.children[1].content, "");
+        assert_eq!(root.to_xml_string(), "<list><item> a b </item><item></item></list>");
+
+        assert!(parse_ok("<a>  </a>").content_is_whitespace());
     }
 
+    #[test]
+    fn moves_children_into_another_node() {
+        let old = parse_ok(r#"<old id="1">text<a>1</a><!-- c --><b>2</b></old>"#);
+        let mut new = XmlNode::element("new");
+        new.children_mut().extend(old.into_children());
+        assert_eq!(new.to_xml_string(), "<new><a>1</a><!-- c --><b>2</b></new>");
+
+        let (name, attributes, content, children) = parse_ok(r#"<old id="1">text<a/></old>"#).into_parts();
+        assert_eq!(name, "old");
+        assert_eq!(attributes, vec![("id".to_string(), "1".to_string())]);
+        assert_eq!(content, "text");
+        let renamed = children.into_iter().fold(XmlNode::element("renamed"), XmlNode::with_child);
+        assert_eq!(renamed.to_xml_string(), "<renamed><a></a></renamed>");
+    }
 
     #[test]
-    fn parses_empty_element() {
-        let xml = "<root></root>";
-        let result = parse_ok(xml);
+    fn allows_greater_than_but_not_cdata_end_in_content() {
+        assert_eq!(parse_ok("<p>a > b</p>").content, "a > b");
+        assert_eq!(parse_ok("<p>]] > ]></p>").content, "]] > ]>");
+        assert_eq!(parse_ok("<p>a ]]&gt; b</p>").content, "a ]]> b");
 
-        assert_eq!(result.name, "root");
-        assert_eq!(result.content, "");
-        assert!(result.children.is_empty());
+        assert!(matches!(parse_err("<p>a ]]> b</p>"), ParseError::SyntaxError { .. }));
+        let tolerant = ParseOptions { auto_close: true, ..ParseOptions::default() };
+        assert!(parse_xml_with("<p>a ]]> b</p>", &tolerant).is_err());
+        assert_eq!(parse_ok(r#"<p v="]]>"/>"#).get_attribute("v"), Some("]]>"));
     }
 
     #[test]
-    fn parses_simple_element() {
-        let xml = "<root>hello</root>";
-        let result = parse_ok(xml);
+    fn limits_attributes_per_element() {
+        let parser = ParserBuilder::new().max_attributes_per_element(2).build();
 
-        assert_eq!(result.name, "root");
-        assert_eq!(result.content, "hello");
-        assert!(result.children.is_empty());
+        assert!(parser.parse(r#"<a x="1" y="2"><b z="3"/></a>"#).is_ok());
+        assert_eq!(
+            parser.parse(r#"<a><b x="1" y="2" z="3"/></a>"#).unwrap_err(),
+            ParseError::LimitExceeded { limit: "max_attributes_per_element", max: 2 }
+        );
+        let many = format!("<a{}/>", (0..1000).map(|i| format!(" a{i}=\"{i}\"")).collect::<String>());
+        assert!(parser.parse(&many).is_err());
+        assert!(parse_xml(&many).is_ok());
     }
 
     #[test]
-    fn ignores_whitespace_in_content() {
-        let xml = "<root>   s p a c e d   </root>";
-        let result = parse_ok(xml);
+    fn collect_text_includes_child_elements() {
+        let root = parse_ok(
+            "<feed><entry><title>A<em>!</em></title></entry><entry><title>B</title></entry></feed>",
+        );
 
-        assert_eq!(result.content, "s p a c e d");
+        assert_eq!(root.collect_text("title"), vec!["A!", "B"]);
+        let own = root.get_nodes("title").iter().map(|n| n.content.as_str()).collect::<Vec<_>>();
+        assert_eq!(own, vec!["A", "B"]);
+        assert!(root.collect_text("missing").is_empty());
     }
 
     #[test]
-    fn parses_nested_elements() {
-        let xml = "<root><a>1</a><b>2</b></root>";
-        let result = parse_ok(xml);
+    fn replaces_node_at_path() {
+        let mut root = parse_ok("<root><!-- c --><item>1</item><item><name>old</name></item></root>");
 
-        assert_eq!(result.name, "root");
-        assert_eq!(result.children.len(), 2);
-        assert_eq!(result.children[0].name, "a");
-        assert_eq!(result.children[0].content, "1");
-        assert_eq!(result.children[1].name, "b");
-        assert_eq!(result.children[1].content, "2");
+        let new_name = XmlNode::element("name").with_content("new");
+        assert!(root.replace_at("/root/item[2]/name", new_name));
+        assert_eq!(
+            root.to_xml_string(),
+            "<root><!-- c --><item>1</item><item><name>new</name></item></root>"
+        );
+
+        assert!(!root.replace_at("/root/item[3]", XmlNode::element("x")));
+        assert!(!root.replace_at("/other", XmlNode::element("x")));
+
+        assert!(root.replace_at("/root", XmlNode::element("fresh")));
+        assert_eq!(root.to_xml_string(), "<fresh></fresh>");
     }
 
     #[test]
-    fn parses_nested_elements_with_content() {
-        let xml = "<root>Content<a>1</a><b>2</b></root>";
-        let result = parse_ok(xml);
-        assert_eq!(result.name, "root");
-        assert_eq!(result.children.len(), 2);
-        assert_eq!(result.content, "Content");
-        assert_eq!(result.children[0].name, "a");
-        assert_eq!(result.children[0].content, "1");
-        assert_eq!(result.children[1].name, "b");
-        assert_eq!(result.children[1].content, "2");
+    fn syntax_error_keeps_pest_error_as_source() {
+        use std::error::Error as _;
+
+        let err = parse_err("<root>\n<a>1</a>");
+        let source = err.source().expect("grammar errors have a source");
+        let pest = source
+            .downcast_ref::<pest::error::Error<Rule>>()
+            .expect("the source is the pest error");
+        assert_eq!(pest.line_col, pest::error::LineColLocation::Pos((2, 9)));
+        assert!(matches!(&err, ParseError::SyntaxError { pest: Some(_), .. }));
+        assert!(err.to_string().starts_with("Unexpected structure or syntax error in XML"));
+
+        // structural errors found while building the tree have no pest error
+        let tolerant = ParseOptions { auto_close: true, ..ParseOptions::default() };
+        let err = parse_xml_with("<a/><b/>", &tolerant).unwrap_err();
+        assert!(matches!(err, ParseError::SyntaxError { pest: None, .. }));
+        assert!(err.source().is_none());
+
+        let err = XmlNode::from_path("tests/samples/invalid.txt").unwrap_err();
+        let inner = err.source().and_then(|source| source.downcast_ref::<ParseError>());
+        assert!(matches!(inner, Some(ParseError::SyntaxError { .. })));
     }
 
     #[test]
-    fn detects_empty_input() {
-        let xml = "";
-        match parse_err(xml) {
-            ParseError::SyntaxError => {}
-            _ => panic!("expected SyntaxError"),
-        }
+    fn timed_parse_reports_phases_and_counts() {
+        let xml = "<root><a x=\"1\">t</a><!-- c --><b><c/></b></root>";
+        let (root, stats) = parse_xml_timed(xml).unwrap();
+
+        assert!(root.structurally_equal(&parse_ok(xml)));
+        assert_eq!(stats.node_count, 5);
+        assert_eq!(stats.bytes, xml.len());
+        assert!(stats.grammar_time + stats.build_time > Duration::ZERO);
+
+        assert!(parse_xml_timed("<root>").is_err());
     }
 
     #[test]
-    fn detects_tag_mismatch() {
-        let xml = "<root><a>1</b></root>";
-        match parse_err(xml) {
-            ParseError::TagMismatch { opening, ending } => {
-                assert_eq!(opening, "a");
-                assert_eq!(ending, "b");
-            }
-            _ => panic!("expected TagMismatch error"),
-        }
+    fn serializes_constructed_mixed_content() {
+        let p = XmlNode::element("p")
+            .with_child(XmlNode::text("Tom & Jerry "))
+            .with_child(XmlNode::element("b").with_content("<3"))
+            .with_child(XmlNode::text(" say "))
+            .with_child(XmlNode::cdata("if (a < b && c]]>d)"));
+
+        let xml = p.to_xml_string();
+        assert_eq!(
+            xml,
+            "<p>Tom &amp; Jerry <b>&lt;3</b> say <![CDATA[if (a < b && c]]]]><![CDATA[>d)]]></p>"
+        );
+        assert_eq!(p.inner_text(), "Tom & Jerry <3 say if (a < b && c]]>d)");
+        assert!(p.children[0].is_leaf() && !p.children[0].is_element());
+
+        let reparsed = parse_ok(&xml);
+        // parsed text runs are trimmed and kept in `content`, not `#text` nodes
+        assert_eq!(reparsed.content, "Tom & Jerrysay");
+        assert_eq!(reparsed.inner_text(), "Tom & Jerrysay<3if (a < b && c]]>d)");
+        assert_eq!(p.canonicalize(), "<p>Tom &amp; Jerry <b>&lt;3</b> say if (a &lt; b &amp;&amp; c]]&gt;d)</p>");
     }
 
     #[test]
-    fn detects_unexpected_structure() {
-        let xml = "<root><a></root>";
-        match parse_err(xml) {
-            ParseError::SyntaxError => {}
-            _ => panic!("expected SyntaxError"),
+    fn get_contents_of_stops_at_first_match() {
+        // the match is the 4th node in document order, followed by many more
+        let mut deep = XmlNode::element("leaf").with_content("last");
+        for _ in 0..500 {
+            deep = XmlNode::element("level").with_child(deep);
         }
+        let root = XmlNode::element("root")
+            .with_child(XmlNode::element("a").with_child(XmlNode::element("target")))
+            .with_child(XmlNode::element("target").with_content("found"))
+            .with_child(deep)
+            .with_child(XmlNode::element("target").with_content("later"));
+
+        let mut found = None;
+        assert_eq!(visits(|| found = root.get_contents_of("target")), 4);
+        assert_eq!(found, Some("found"));
+        assert_eq!(visits(|| {
+            let _ = root.get_contents_of("leaf");
+        }), 505);
     }
-    
+
     #[test]
-    fn detects_file_error() {
-        let result = XmlNode::from_path("nonexistent.xml");
-        match result {
-            Err(ParseError::IoError(_)) => {}
-            _ => panic!("expected IoError for missing file"),
-        }
+    fn comments_keep_their_position_among_element_children() {
+        let names = |node: &XmlNode| node.children.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+
+        let root = parse_ok("<r><a/><!--x--><b/></r>");
+        assert_eq!(names(&root), vec!["a", "#comment", "b"]);
+        assert_eq!(root.children[1].content, "<!--x-->");
+        assert_eq!(root.to_xml_string(), "<r><a></a><!--x--><b></b></r>");
+
+        let root = parse_ok("<r><!--1--><a/>text<!--2--><![CDATA[c]]><b/><!--3--></r>");
+        assert_eq!(names(&root), vec!["#comment", "a", "#comment", "#cdata", "b", "#comment"]);
+
+        let tolerant = ParseOptions { auto_close: true, ..ParseOptions::default() };
+        let root = parse_xml_with("<r><a/><!--x--><b/></r>", &tolerant).unwrap();
+        assert_eq!(names(&root), vec!["a", "#comment", "b"]);
     }
+
     #[test]
-    fn parses_comment() {
-        let xml = "<root><!-- this is a comment --></root>";
-        let node = parse_ok(xml);
+    fn prolog_comments_follow_a_bare_declaration() {
+        let document = parse_document("<?xml?><!--c--><root/>").unwrap();
+        assert_eq!(document.prolog.len(), 1);
+        assert_eq!(document.prolog[0].content, "<!--c-->");
+        assert_eq!(document.root.name, "root");
 
-        assert_eq!(node.name, "root");
-        assert_eq!(node.children[0].content, "<!-- this is a comment -->");
-        assert_eq!(node.content, "");
+        let err = parse_err("<!--c--><?xml?><root/>");
+        assert!(matches!(err, ParseError::MisplacedDeclaration { line: 1 }));
+        let err = parse_err("<?pi x?><?xml?><root/>");
+        assert!(matches!(err, ParseError::MisplacedDeclaration { line: 1 }));
     }
 
     #[test]
-    fn parses_declaration() {
-        let xml = r#"<?xml ?><root></root>"#;
-        let node = parse_ok(xml);
+    fn estimated_size_covers_every_string() {
+        let node = XmlNode {
+            name: String::with_capacity(10),
+            attributes: vec![(String::with_capacity(3), String::with_capacity(5))],
+            children: vec![XmlNode { content: String::with_capacity(7), ..XmlNode::default() }],
+            ..XmlNode::default()
+        };
+        assert_eq!(node.node_count(), 2);
+        assert_eq!(node.estimated_size(), 25);
 
-        assert_eq!(node.name, "root");
-        assert!(node.children.is_empty());
+        let parsed = parse_ok(r#"<root a="1">text<!-- c --><b/></root>"#);
+        assert!(parsed.estimated_size() >= "roota1text<!-- c -->b".len());
     }
 
     #[test]
-    fn parses_empty_element_tag() {
-        let xml = "<root><empty /></root>";
-        let node = parse_ok(xml);
+    fn retain_children_drops_comments_at_every_level() {
+        let mut node = parse_ok("<root><!-- a --><x><!-- b --><y/></x><![CDATA[c]]></root>");
+        node.retain_children(|child| child.name != "#comment");
+        assert_eq!(node.to_xml_string(), "<root><x><y></y></x><![CDATA[c]]></root>");
 
-        assert_eq!(node.name, "root");
-        assert_eq!(node.children.len(), 1);
-        assert_eq!(node.children[0].name, "empty");
-        assert!(node.children[0].children.is_empty());
-        assert_eq!(node.children[0].content, "");
+        let mut visited = Vec::new();
+        let mut node = parse_ok("<root><drop><inner/></drop><keep><inner/></keep></root>");
+        node.retain_children(|child| {
+            visited.push(child.name.clone());
+            child.name != "drop"
+        });
+        assert_eq!(visited, ["drop", "keep", "inner"]);
+        assert_eq!(node.to_xml_string(), "<root><keep><inner></inner></keep></root>");
     }
 
     #[test]
-    fn parses_element_with_attribute() {
-        let xml = r#"<root><a attr="value"></a></root>"#;
-        let node = parse_ok(xml);
+    fn semantically_equal_ignores_formatting() {
+        let compact = parse_ok(r#"<doc id="1" lang="en"><p>hello world</p><p/></doc>"#);
+        let formatted = parse_ok(
+            "<doc lang=\"en\"\n     id=\"1\">\n  <!-- intro -->\n  <p>\n    hello\n    world\n  </p>\n  <p></p>\n</doc>\n",
+        );
+        assert!(compact.semantically_equal(&formatted));
+        assert!(formatted.semantically_equal(&compact));
+        assert!(!compact.structurally_equal(&formatted));
 
-        assert_eq!(node.name, "root");
-        assert_eq!(node.children[0].attributes[0].0, "attr");
-        assert_eq!(node.content, "");
+        for different in [
+            r#"<doc id="1" lang="en"><p>hello  there</p><p/></doc>"#,
+            r#"<doc id="1" lang="de"><p>hello world</p><p/></doc>"#,
+            r#"<doc id="1" lang="en"><p>hello world</p></doc>"#,
+            r#"<doc id="1" lang="en"><p>hello world</p><q/></doc>"#,
+            r#"<doc id="1" lang="en"><p>hello world</p><p/><![CDATA[x]]></doc>"#,
+        ] {
+            assert!(!compact.semantically_equal(&parse_ok(different)), "{}", different);
+        }
     }
 
     #[test]
-    fn parses_element_with_attributes() {
-        let xml = r#"<root><a attr="value" id="2"></a></root>"#;
-        let node = parse_ok(xml);
+    fn inner_text_puts_own_text_before_children() {
+        let node = parse_ok("<p>a<b>x</b>c<i>y<u>z</u>w</i></p>");
+        assert_eq!(node.inner_text(), "acxywz");
+        assert_eq!(node.collect_text("i"), ["ywz"]);
+    }
 
-        assert_eq!(node.name, "root");
-        assert_eq!(node.children[0].attributes[0].0, "attr");
-        assert_eq!(node.children[0].attributes[1].1, "2");
-        assert_eq!(node.content, "");
+    #[test]
+    fn content_lookups_still_find_cdata_and_pi_nodes() {
+        let mut root = parse_ok("<r><!-- c --><![CDATA[x]]></r>");
+        root.children.push(XmlNode { name: "#pi".to_string(), content: "<?p d?>".to_string(), ..XmlNode::default() });
+
+        assert_eq!(root.get_contents_of("#cdata"), Some("<![CDATA[x]]>"));
+        assert_eq!(root.get_contents_of("#pi"), Some("<?p d?>"));
+        assert_eq!(root.value_of("#cdata", None), Some("<![CDATA[x]]>"));
+        assert_eq!(root.try_get_contents_of("#pi"), Ok("<?p d?>"));
+
+        assert_eq!(root.get_contents_of("#comment"), None);
+        assert_eq!(root.value_of("#comment", None), None);
+        assert_eq!(
+            root.try_get_contents_of("#comment"),
+            Err(ContentError::NotFound { tag: "#comment".to_string() })
+        );
     }
 
     #[test]
-    fn parses_cdata_xml() {
-        let xml = "<![CDATA[Some <encoded> & text]]>";
-        let node = parse_ok(xml);
-        assert_eq!(node.name, "#cdata");
-        assert_eq!(node.content, "<![CDATA[Some <encoded> & text]]>");
-        assert!(node.attributes.is_empty());
-        assert!(node.children.is_empty());
+    fn text_search_ignores_only_ascii_case() {
+        let root = parse_ok("<r><a>Hello World</a><b>Über café</b></r>");
+
+        assert!(root.children[0].contains_text_ci("o wOR"));
+        assert!(root.children[0].contains_text_ci(""));
+        assert!(!root.children[1].contains_text_ci("ÜBER CAFÉ"));
+        assert!(root.children[1].contains_text_ci("Über CAFé"));
+        let found = root.find_text_ci("HELLO").iter().map(|n| n.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(found, ["a"]);
     }
 
     #[test]
-    fn parses_cdata_inside_xml() {
-        let xml = "<root><![CDATA[5 < 10 && x > 3]]></root>";
-        let node = parse_ok(xml);
-        assert_eq!(node.name, "root");
-        assert!(node.attributes.is_empty());
-        assert_eq!(node.children.len(), 1);
+    fn finds_duplicates_among_many_attributes() {
+        let attrs = (0..50).map(|i| format!(" a{}=\"{}\"", i, i)).collect::<String>();
+        let strict = Parser::builder().reject_duplicate_attributes(true).build();
 
-        let cdata = &node.children[0];
-        assert_eq!(cdata.name, "#cdata");
-        assert_eq!(cdata.content, "<![CDATA[5 < 10 && x > 3]]>");
+        assert_eq!(parse_ok(&format!("<r{}/>", attrs)).attributes.len(), 50);
+        let err = strict.parse(&format!("<r{} a7=\"x\"/>", attrs)).unwrap_err();
+        assert!(matches!(err, ParseError::DuplicateAttribute { ref name, .. } if name == "a7"));
+
+        let (_, warnings) =
+            parse_xml_with_warnings(&format!("<r a1=\"x\"{}/>", attrs), &ParseOptions::default()).unwrap();
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn sibling_lookups_accept_clark_names() {
+        let root = parse_ok(
+            r#"<feed xmlns:a="urn:atom"><x/><a:entry>1</a:entry><entry>2</entry><a:entry>3</a:entry></feed>"#,
+        );
+        let tag = "{urn:atom}entry";
+
+        let limited = root.get_nodes_limited(tag, 1);
+        assert_eq!(limited.len(), 1);
+        assert_eq!(limited[0].content, "1");
+        assert_eq!(visits(|| drop(root.get_nodes_limited(tag, 1))), 3);
+
+        let indexed: Vec<(usize, &str)> = root
+            .get_nodes_indexed(tag)
+            .into_iter()
+            .map(|(index, node)| (index, node.content.as_str()))
+            .collect();
+        assert_eq!(indexed, [(1, "1"), (2, "3")]);
+
+        assert_eq!(root.nearest(tag).map(|n| n.content.as_str()), Some("1"));
+        assert!(root.nearest("{urn:other}entry").is_none());
+    }
+
+    #[test]
+    fn builder_configures_parser() {
+        let parser = Parser::builder().preserve_whitespace(true).max_depth(2).build();
+        assert!(parser.options().preserve_whitespace);
+
+        let node = parser.parse("<root>\n  <a> x </a>\n</root>").expect("expected valid XML");
+        assert_eq!(node.content, "\n  \n");
+        assert_eq!(node.children[0].content, " x ");
+
+        match parser.parse("<root><a><b></b></a></root>") {
+            Err(ParseError::LimitExceeded { limit, max }) => {
+                assert_eq!(limit, "max_depth");
+                assert_eq!(max, 2);
+            }
+            _ => panic!("expected LimitExceeded error"),
+        }
+    }
+
+    #[test]
+    fn mutates_child_and_round_trips() {
+        let xml = r#"<root><a id="1">old</a><!-- note --><b /></root>"#;
+        let mut node = parse_ok(xml);
+
+        let child = node.child_mut(0).expect("expected first child");
+        child.set_content("new");
+        child.set_attribute("id", "2");
+        child.set_attribute("lang", "en");
+        node.children_mut().remove(2);
+        assert!(node.child_mut(5).is_none());
+
+        let serialized = node.to_xml_string();
+        assert_eq!(serialized, r#"<root><a id="2" lang="en">new</a><!-- note --></root>"#);
+
+        let reparsed = parse_ok(&serialized);
+        assert_eq!(reparsed.children[0].content, "new");
+        assert_eq!(reparsed.children[0].get_attribute("lang"), Some("en"));
+        assert_eq!(reparsed.children.len(), 2);
     }
 
 }
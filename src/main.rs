@@ -1,10 +1,11 @@
-use std::fmt;
-use xml_parser::{XmlNode, ParseError};
+use std::io::{self, IsTerminal};
+use std::{fmt, fs};
+use xml_parser::{DisplayOptions, Indent, PrettyOptions, XmlNode, ParseError, parse_xml};
 
 fn main() {
     if let Err(e) = run_cli() {
         eprintln!("Error: {}", e);
-        std::process::exit(1);
+        std::process::exit(e.exit_code());
     }
 }
 
@@ -17,6 +18,7 @@ fn run_cli() -> Result<(), CliError> {
 
     match args[1].as_str() {
         "parse" => handle_parse(&args)?,
+        "grep" => handle_grep(&args)?,
         "help" | "-help" => print_help(),
         "credits" => print_credits(),
         cmd => return Err(CliError::UnknownCommand(cmd.to_string())),
@@ -30,7 +32,23 @@ fn run_cli() -> Result<(), CliError> {
 enum CliError {
     MissingArgs(&'static str),
     UnknownCommand(String),
+    NotFound(String),
     Parse(ParseError),
+    Write { dir: String, source: io::Error },
+}
+
+impl CliError {
+    // 2: bad usage, 3: file could not be read, 4: invalid XML,
+    // 5: output could not be written, 1: anything else
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::MissingArgs(_) | CliError::UnknownCommand(_) => 2,
+            CliError::Parse(ParseError::IoError(_)) => 3,
+            CliError::Parse(_) => 4,
+            CliError::Write { .. } => 5,
+            CliError::NotFound(_) => 1,
+        }
+    }
 }
 
 impl fmt::Display for CliError {
@@ -42,7 +60,9 @@ impl fmt::Display for CliError {
             CliError::UnknownCommand(command) => {
                 write!(f, "Unknown command: '{}'.\nType -help for more information.", command)
             }
+            CliError::NotFound(what) => write!(f, "No such tag: {}.", what),
             CliError::Parse(e) => write!(f, "{}.\nType -help for more information.", e),
+            CliError::Write { dir, source } => write!(f, "Could not write to {}: {}.", dir, source.kind()),
         }
     }
 }
@@ -54,19 +74,83 @@ impl From<ParseError> for CliError {
 }
 
 fn handle_parse(args: &[String]) -> Result<(), CliError> {
+    let (args, color) = take_color_option(args)?;
+    let args = args.as_slice();
+    let view = DisplayOptions { color, ..DisplayOptions::default() };
+
     if args.len() < 3 {
         return Err(CliError::MissingArgs("path to XML file"));
     }
 
     let path = &args[2];
-    let tree = XmlNode::from_path(path)?;
+    // read once: `--format` also detects the indentation of the source
+    let source = fs::read_to_string(path).map_err(ParseError::from)?;
+    let tree = parse_xml(&source)
+        .map_err(|err| ParseError::InFile { path: path.to_string(), source: Box::new(err) })?;
 
     if args.len() == 3 {
-        println!("{}", tree);
+        println!("{}", tree.display_with(&view));
         return Ok(());
     }
 
     match args[3].as_str() {
+        "--pretty" => println!("{}", tree.display_with(&view)),
+        "--raw" => println!("{}", tree.to_xml_string()),
+        "--format" => {
+            let indent = match args.get(4).map(String::as_str) {
+                Some("--tabs") => Indent::Tabs,
+                Some("--indent") => args
+                    .get(5)
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .map(Indent::Spaces)
+                    .ok_or(CliError::MissingArgs("number of spaces for --indent"))?,
+                Some(option) => return Err(CliError::UnknownCommand(option.to_string())),
+                // keep the source's own indentation style when there is one
+                None => Indent::detect(&source).unwrap_or_default(),
+            };
+            print!("{}", tree.to_xml_string_pretty(&PrettyOptions { indent, ..PrettyOptions::default() }));
+        }
+        "--preview" => {
+            let depth = args
+                .get(4)
+                .and_then(|n| n.parse::<usize>().ok())
+                .ok_or(CliError::MissingArgs("depth number for --preview"))?;
+            print!("{}", tree.display_with(&DisplayOptions { max_depth: Some(depth), ..view }));
+        }
+        "--schema" => print!("{}", tree.infer_schema()),
+        "--report" => {
+            let tag = args.get(4).ok_or(CliError::MissingArgs("tag name for --report"))?;
+            let nodes = tree.get_nodes(tag);
+
+            println!("Found {} <{}> tag(s):", nodes.len(), tag);
+            for (i, node) in nodes.iter().enumerate() {
+                print_report(i + 1, node);
+            }
+        }
+        "--subtree" => {
+            let selector = args.get(4).ok_or(CliError::MissingArgs("tag name for --subtree"))?;
+            let (tag, index) = parse_indexed_tag(selector)?;
+            let node = tree
+                .get_nodes(tag)
+                .get(index - 1)
+                .copied()
+                .ok_or_else(|| CliError::NotFound(selector.to_string()))?;
+            println!("{}", node.to_xml_string());
+        }
+        "--split" => {
+            let tag = args.get(4).ok_or(CliError::MissingArgs("tag name for --split"))?;
+            let dir = match args.get(5).map(String::as_str) {
+                Some("--outdir") => {
+                    args.get(6).ok_or(CliError::MissingArgs("directory for --outdir"))?
+                }
+                Some(option) => return Err(CliError::UnknownCommand(option.to_string())),
+                None => return Err(CliError::MissingArgs("--outdir for --split")),
+            };
+            let written = tree
+                .write_children_named(tag, dir)
+                .map_err(|source| CliError::Write { dir: dir.to_string(), source })?;
+            println!("Wrote {} <{}> file(s) to {}", written, tag, dir);
+        }
         "-get" => {
             let tag = args.get(4).ok_or(CliError::MissingArgs("tag name for -get"))?;
             match tree.get_contents_of(tag) {
@@ -76,15 +160,13 @@ fn handle_parse(args: &[String]) -> Result<(), CliError> {
         }
         "-get_all" => {
             let tag = args.get(4).ok_or(CliError::MissingArgs("tag name for -get_all"))?;
-            let results = tree.get_nodes(tag)
-                            .iter()
-                            .map(|node| node.content.clone()).collect::<Vec<String>>();
-
-            println!("Found {} <{}> tag(s):", results.len(), tag);
-            for (i, item) in results.iter().enumerate() {
-                match item {
-                    content if !content.is_empty() => println!("{}. {}", i + 1, content),
-                    _ => println!("{}. None", i + 1),
+            let nodes = tree.get_nodes(tag);
+
+            println!("Found {} <{}> tag(s):", nodes.len(), tag);
+            for (i, node) in nodes.iter().enumerate() {
+                match node.content.as_str() {
+                    "" => println!("{}. None", i + 1),
+                    content => println!("{}. {}", i + 1, content),
                 }
             }
         }
@@ -93,7 +175,89 @@ fn handle_parse(args: &[String]) -> Result<(), CliError> {
     Ok(())
 }
 
+// `grep <file> <substring> [--attrs]`: prints the path of every element whose
+// content (and with --attrs, an attribute value) contains the substring
+fn handle_grep(args: &[String]) -> Result<(), CliError> {
+    let include_attributes = args.iter().skip(2).any(|arg| arg == "--attrs");
+    let mut operands = args.iter().skip(2).filter(|arg| *arg != "--attrs");
+    let path = operands.next().ok_or(CliError::MissingArgs("path to XML file"))?;
+    let needle = operands.next().ok_or(CliError::MissingArgs("text to search for"))?;
+    if let Some(extra) = operands.next() {
+        return Err(CliError::UnknownCommand(extra.to_string()));
+    }
+
+    let tree = XmlNode::from_path(path)?;
+    let mut matches = 0;
+    for (node_path, node) in tree.descendants_with_path() {
+        if node.contains_text(needle) {
+            println!("{}: {}", node_path, node.content);
+            matches += 1;
+        }
+        if include_attributes {
+            for (name, value) in node.attributes.iter().filter(|(_, v)| v.contains(needle.as_str())) {
+                println!("{}/@{}: {}", node_path, name, value);
+                matches += 1;
+            }
+        }
+    }
+
+    println!("Found {} match(es) for '{}'", matches, needle);
+    Ok(())
+}
+
+// removes `--color auto|always|never` from the arguments and decides whether
+// to color the tree view: `auto` (the default) colors only a terminal stdout
+// and honours NO_COLOR
+fn take_color_option(args: &[String]) -> Result<(Vec<String>, bool), CliError> {
+    let mut args = args.to_vec();
+    let Some(position) = args.iter().position(|arg| arg == "--color") else {
+        return Ok((args, auto_color()));
+    };
+    if position + 1 >= args.len() {
+        return Err(CliError::MissingArgs("auto, always or never for --color"));
+    }
+
+    let mode = args.remove(position + 1);
+    args.remove(position);
+    let color = match mode.as_str() {
+        "auto" => auto_color(),
+        "always" => true,
+        "never" => false,
+        _ => return Err(CliError::UnknownCommand(mode)),
+    };
+    Ok((args, color))
+}
+
+fn auto_color() -> bool {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    !no_color && io::stdout().is_terminal()
+}
+
+// prints content and attributes of one node as a block with aligned keys
+fn print_report(number: usize, node: &XmlNode) {
+    let content = if node.content.is_empty() { "None" } else { node.content.as_str() };
+    let rows = std::iter::once(("content", content))
+        .chain(node.attributes.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .collect::<Vec<(&str, &str)>>();
+    let width = rows.iter().map(|(key, _)| key.chars().count()).max().unwrap_or(0);
+
+    println!("{}. <{}>", number, node.name);
+    for (key, value) in rows {
+        println!("   {:<width$} : {}", key, value, width = width);
+    }
+}
+
+// splits `tag[n]` into the tag and its 1-based index; a bare `tag` means `tag[1]`
+fn parse_indexed_tag(selector: &str) -> Result<(&str, usize), CliError> {
+    let Some((tag, rest)) = selector.split_once('[') else {
+        return Ok((selector, 1));
+    };
 
+    match rest.strip_suffix(']').and_then(|n| n.parse::<usize>().ok()) {
+        Some(index) if index > 0 => Ok((tag, index)),
+        _ => Err(CliError::MissingArgs("a --subtree selector of the form tag or tag[n] with n >= 1")),
+    }
+}
 
 fn print_help() {
     println!(
@@ -102,13 +266,36 @@ XML Parser CLI
 
 Usage:
   parse <path/to/file>                Parse XML file and print its tree.
+  parse <path/to/file> --pretty       Same as above: print the indented tree view.
+  parse <path/to/file> --raw          Print the document re-serialized as XML.
+  parse <path/to/file> --format       Print the document as indented XML, reusing the file's indentation.
+        --format --indent [n] | --tabs  Indent with n spaces or with tabs instead.
+  parse <path/to/file> --preview [n]  Print the tree only down to n levels below the root.
+        --color [auto|always|never]   Color the tree view; auto colors only a terminal
+                                      and is turned off by the NO_COLOR variable.
+  parse <path/to/file> --schema       Print the inferred structure: children and attributes of
+                                      every element with multiplicity 1, ? (optional) or * (many).
+  parse <path/to/file> --report [tag]  List content and all attributes of every node with given tag.
+  parse <path/to/file> --subtree [tag] Print the first <tag> subtree as XML; use tag[n] for the nth.
+  parse <path/to/file> --split [tag] --outdir [dir]
+                                      Write each <tag> child of the root to dir/tag_N.xml.
   parse <path/to/file> -get [tag]     Find and print contents of first node with given tag.
   parse <path/to/file> -get_all [tag] Find and list contents of all nodes with given tag.
+  grep <path/to/file> <text>          Print the path and content of every element containing text.
+        --attrs                       Search attribute values too.
 
 Other commands:
   help, -help        Show this help message.
   credits            Show authorship information.
 
+Exit codes:
+  0  Success. -get, -get_all and --report also succeed when no tag matches.
+  1  The tag selected by --subtree was not found.
+  2  Missing argument, unknown command or malformed --subtree selector.
+  3  The file could not be read.
+  4  The file is not valid XML.
+  5  An output file could not be written (--split).
+
 To Run tests:
   cargo test
 "#
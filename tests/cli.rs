@@ -1,4 +1,4 @@
-use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
 use predicates::prelude::*;
 use anyhow::Result;
 
@@ -6,7 +6,7 @@ use anyhow::Result;
 fn cli_parses_file_and_prints_tree() -> Result<()> {
     let path = "tests/samples/simple.txt";
 
-    Command::cargo_bin("xml_parser")?
+    cargo_bin_cmd!("xml_parser")
         .args(["parse", path])
         .assert()
         .success()
@@ -15,11 +15,145 @@ fn cli_parses_file_and_prints_tree() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn cli_prints_raw_and_pretty_output() -> Result<()> {
+    let path = "tests/samples/simple.txt";
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", path, "--raw"])
+        .assert()
+        .success()
+        .stdout("<root><item>Hello there</item></root>\n");
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", path, "--pretty"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("<root>\n").and(predicate::str::contains("Hello there")));
+
+    Ok(())
+}
+
+#[test]
+fn cli_formats_with_tabs_or_spaces() -> Result<()> {
+    let path = "tests/samples/simple.txt";
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", path, "--format", "--tabs"])
+        .assert()
+        .success()
+        .stdout("<root>\n\t<item>Hello there</item>\n</root>\n");
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", path, "--format", "--indent", "4"])
+        .assert()
+        .success()
+        .stdout("<root>\n    <item>Hello there</item>\n</root>\n");
+
+    // 5names.xml is indented with tabs
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/5names.xml", "--format"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\n\t<folder>obj0707</folder>\n"));
+
+    Ok(())
+}
+
+#[test]
+fn cli_colors_only_when_forced_or_on_terminal() -> Result<()> {
+    let path = "tests/samples/simple.txt";
+
+    // stdout is a pipe here, so `auto` and the default print no ANSI codes
+    for args in [vec!["parse", path], vec!["parse", path, "--color", "auto", "--pretty"]] {
+        cargo_bin_cmd!("xml_parser")
+            .args(args)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("\x1b[").not());
+    }
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", path, "--color", "always"])
+        .env("NO_COLOR", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\x1b[34m<root\x1b[0m"));
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", path, "--color", "sometimes"])
+        .assert()
+        .code(2);
+
+    Ok(())
+}
+
+#[test]
+fn cli_extracts_subtree() -> Result<()> {
+    let path = "tests/samples/everything.txt";
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", path, "--subtree", "item[2]"])
+        .assert()
+        .success()
+        .stdout("<item id=\"2\"><empty></empty></item>\n");
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", path, "--subtree", "item[3]"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("No such tag: item[3]"));
+
+    for selector in ["item[abc]", "item[0]", "item[2"] {
+        cargo_bin_cmd!("xml_parser")
+            .args(["parse", path, "--subtree", selector])
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains("--subtree selector of the form tag or tag[n]"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn cli_previews_tree_to_depth() -> Result<()> {
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/everything.txt", "--preview", "1"])
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("… (1 more children)")
+                .and(predicate::str::contains("<empty>").not()),
+        );
+
+    Ok(())
+}
+
+#[test]
+fn cli_reports_content_and_attributes() -> Result<()> {
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/everything.txt", "--report", "item"])
+        .assert()
+        .success()
+        .stdout(
+            "Found 2 <item> tag(s):\n\
+             1. <item>\n\
+             \x20  content : Hello there\n\
+             \x20  id      : 1\n\
+             \x20  type    : greeting\n\
+             2. <item>\n\
+             \x20  content : None\n\
+             \x20  id      : 2\n",
+        );
+
+    Ok(())
+}
+
 #[test]
 fn cli_gets_single_tag_content() -> Result<()> {
     let path = "tests/samples/simple.txt";
 
-    Command::cargo_bin("xml_parser")?
+    cargo_bin_cmd!("xml_parser")
         .args(["parse", path, "-get", "item"])
         .assert()
         .success()
@@ -28,26 +162,169 @@ fn cli_gets_single_tag_content() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn cli_lists_all_tag_contents() -> Result<()> {
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/everything.txt", "-get_all", "item"])
+        .assert()
+        .success()
+        .stdout("Found 2 <item> tag(s):\n1. Hello there\n2. None\n");
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/everything.txt", "-get_all", "missing"])
+        .assert()
+        .success()
+        .stdout("Found 0 <missing> tag(s):\n");
+
+    Ok(())
+}
+
 #[test]
 fn cli_handles_unknown_command() -> Result<()> {
-    Command::cargo_bin("xml_parser")?
+    cargo_bin_cmd!("xml_parser")
         .arg("strange command")
         .assert()
-        .failure()
+        .code(2)
         .stderr(predicate::str::contains("Unknown command"));
 
     Ok(())
 }
 
+#[test]
+fn cli_exits_with_usage_code_for_missing_arguments() -> Result<()> {
+    cargo_bin_cmd!("xml_parser").assert().code(2);
+    cargo_bin_cmd!("xml_parser").args(["parse"]).assert().code(2);
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/simple.txt", "-get"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Missing argument"));
+
+    Ok(())
+}
+
+#[test]
+fn cli_names_file_with_parse_error() -> Result<()> {
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/invalid.txt"])
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains("Error in tests/samples/invalid.txt: "));
+
+    Ok(())
+}
+
+#[test]
+fn cli_reports_empty_file() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("xml_parser_cli_empty_{}.xml", std::process::id()));
+    std::fs::write(&path, "\n  \n")?;
+
+    let assert = cargo_bin_cmd!("xml_parser").arg("parse").arg(&path).assert();
+    std::fs::remove_file(&path)?;
+    assert.code(4).stderr(predicate::str::contains("The document is empty"));
+
+    Ok(())
+}
+
+#[test]
+fn cli_splits_children_into_files() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("xml_parser_cli_split_{}", std::process::id()));
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/5names.xml", "--split", "object", "--outdir"])
+        .arg(&dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Wrote 5 <object> file(s)"));
+
+    let third = std::fs::read_to_string(dir.join("object_3.xml"))?;
+    assert!(third.starts_with("<object><name>plastic</name>"));
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn cli_exit_codes_for_lookups_and_failed_writes() -> Result<()> {
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/simple.txt", "-get", "missing"])
+        .assert()
+        .code(0)
+        .stdout("No <missing> node found.\n");
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/simple.txt", "--subtree", "missing"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("No such tag: missing"));
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/5names.xml", "--split", "object", "--outdir"])
+        .arg("tests/samples/simple.txt/objects")
+        .assert()
+        .code(5)
+        .stderr(predicate::str::contains("Could not write to tests/samples/simple.txt/objects"));
+
+    Ok(())
+}
+
 #[test]
 fn cli_reports_missing_file() -> Result<()> {
     let path = "tests/samples/missing.xml";
 
-    Command::cargo_bin("xml_parser")?
+    cargo_bin_cmd!("xml_parser")
         .args(["parse", path])
         .assert()
-        .failure()
-        .stderr(predicate::str::contains("The system cannot find the file specified"));
+        .code(3)
+        .stderr(predicate::str::contains("File I/O error: entity not found"));
+
+    Ok(())
+}
+
+#[test]
+fn cli_greps_content_and_attributes() -> Result<()> {
+    let path = "tests/samples/everything.txt";
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["grep", path, "there"])
+        .assert()
+        .success()
+        .stdout("/root/item[1]: Hello there\nFound 1 match(es) for 'there'\n");
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["grep", path, "greet"])
+        .assert()
+        .success()
+        .stdout("Found 0 match(es) for 'greet'\n");
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["grep", path, "--attrs", "greet"])
+        .assert()
+        .success()
+        .stdout("/root/item[1]/@type: greeting\nFound 1 match(es) for 'greet'\n");
+
+    cargo_bin_cmd!("xml_parser")
+        .args(["grep", "tests/samples/5names.xml", "Unspecified"])
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("/annotation/object[3]/pose: Unspecified\n")
+                .and(predicate::str::ends_with("Found 5 match(es) for 'Unspecified'\n")),
+        );
+
+    cargo_bin_cmd!("xml_parser").args(["grep", path]).assert().code(2);
+
+    Ok(())
+}
+
+#[test]
+fn cli_prints_inferred_schema() -> Result<()> {
+    cargo_bin_cmd!("xml_parser")
+        .args(["parse", "tests/samples/everything.txt", "--schema"])
+        .assert()
+        .success()
+        .stdout("<root>\n  * item\n<item>\n  1 @id\n  ? @type\n  ? empty\n<empty>\n");
 
     Ok(())
 }
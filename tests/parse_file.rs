@@ -1,10 +1,11 @@
-use xml_parser::XmlNode;
+use xml_parser::{parse_xml, Multiplicity, ParseError, XmlNode};
 use anyhow::Result;
+use std::path::{Path, PathBuf};
 
 #[test]
 fn parses_simple_file() -> Result<()> {
     let path = "tests/samples/simple.txt";
-    let node = XmlNode::from_path(&path)
+    let node = XmlNode::from_path(path)
         .map_err(|e| anyhow::anyhow!("failed to parse {:?}: {}", path, e))?;
 
     assert_eq!(node.name, "root");
@@ -14,18 +15,40 @@ fn parses_simple_file() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn from_path_accepts_str_and_path() -> Result<()> {
+    let as_str = XmlNode::from_path("tests/samples/simple.txt")?;
+    let as_path = XmlNode::from_path(Path::new("tests/samples/simple.txt"))?;
+    let as_path_buf = XmlNode::from_path(PathBuf::from("tests/samples").join("simple.txt"))?;
+
+    assert!(as_str.structurally_equal(&as_path));
+    assert!(as_str.structurally_equal(&as_path_buf));
+    Ok(())
+}
+
 #[test]
 fn detects_invalid_file() -> Result<()> {
     let path = "tests/samples/invalid.txt";
-    let result = XmlNode::from_path(&path);
+    let result = XmlNode::from_path(path);
     assert!(result.is_err(), "expected error for invalid XML");
     Ok(())
 }
 
+#[test]
+fn parse_errors_name_the_file() -> Result<()> {
+    let path = "tests/samples/invalid.txt";
+    let err = XmlNode::from_path(path).unwrap_err();
+
+    assert!(matches!(&err, ParseError::InFile { path: p, .. } if p == path));
+    assert!(err.to_string().starts_with("Error in tests/samples/invalid.txt: "));
+    assert!(std::error::Error::source(&err).is_some());
+    Ok(())
+}
+
 #[test]
 fn fails_if_file_missing() -> Result<()> {
     let path = "tests/samples/missing.xml";
-    let result = XmlNode::from_path(&path);
+    let result = XmlNode::from_path(path);
     assert!(result.is_err(), "expected error for missing file");
     Ok(())
 }
@@ -33,7 +56,7 @@ fn fails_if_file_missing() -> Result<()> {
 #[test]
 fn parses_2names_file() -> Result<()> {
     let path = "tests/samples/2names.xml";
-    let node = XmlNode::from_path(&path)
+    let node = XmlNode::from_path(path)
         .map_err(|e| anyhow::anyhow!("failed to parse {:?}: {}", path, e))?;
 
     let names = node.get_nodes("name")
@@ -47,7 +70,7 @@ fn parses_2names_file() -> Result<()> {
 #[test]
 fn parses_5names_file() -> Result<()> {
     let path = "tests/samples/5names.xml";
-    let node = XmlNode::from_path(&path)
+    let node = XmlNode::from_path(path)
         .map_err(|e| anyhow::anyhow!("failed to parse {:?}: {}", path, e))?;
 
     let names = node.get_nodes("name")
@@ -61,10 +84,52 @@ fn parses_5names_file() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn flattens_5names_file() -> Result<()> {
+    let path = "tests/samples/5names.xml";
+    let node = XmlNode::from_path(path)
+        .map_err(|e| anyhow::anyhow!("failed to parse {:?}: {}", path, e))?;
+
+    let pairs = node.flatten();
+    let value_of = |path: &str| {
+        pairs.iter().find(|(p, _)| p == path).map(|(_, v)| v.as_str())
+    };
+
+    assert_eq!(pairs[0], ("/annotation/folder".to_string(), "obj0707".to_string()));
+    assert_eq!(value_of("/annotation/size/width"), Some("480"));
+    assert_eq!(value_of("/annotation/object[1]/name"), Some("rubber"));
+    assert_eq!(value_of("/annotation/object[5]/bndbox/ymax"), Some("315"));
+    assert_eq!(value_of("/annotation/object[1]"), None);
+    Ok(())
+}
+
+#[test]
+fn counts_all_elements_in_5names_file() -> Result<()> {
+    let node = XmlNode::from_path("tests/samples/5names.xml")?;
+
+    // 11 in the header, 10 in each of the 5 objects
+    assert_eq!(node.all_elements().len(), 61);
+    assert_eq!(node.get_nodes("*").len(), 61);
+    assert_eq!(node.all_elements()[1].name, "folder");
+    Ok(())
+}
+
+#[test]
+fn counts_nodes_of_samples() -> Result<()> {
+    let names = XmlNode::from_path("tests/samples/5names.xml")?;
+    assert_eq!(names.node_count(), 61);
+
+    // root, two items, <empty />, a comment and a CDATA section
+    let everything = XmlNode::from_path("tests/samples/everything.txt")?;
+    assert_eq!(everything.node_count(), 6);
+    assert!(everything.estimated_size() > everything.children[0].estimated_size());
+    Ok(())
+}
+
 #[test]
 fn parses_everything() -> Result<()> {
     let path = "tests/samples/everything.txt";
-    let node = XmlNode::from_path(&path)
+    let node = XmlNode::from_path(path)
         .map_err(|e| anyhow::anyhow!("failed to parse {:?}: {}", path, e))?;
 
     assert_eq!(node.children.len(), 4);
@@ -89,10 +154,35 @@ fn parses_everything() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn serialized_samples_reparse_to_equal_trees() -> Result<()> {
+    for path in [
+        "tests/samples/simple.txt",
+        "tests/samples/2names.xml",
+        "tests/samples/5names.xml",
+        "tests/samples/everything.txt",
+    ] {
+        let node = XmlNode::from_path(path)
+            .map_err(|e| anyhow::anyhow!("failed to parse {:?}: {}", path, e))?;
+
+        let serialized = node.to_xml_string();
+        let reparsed = parse_xml(&serialized)
+            .map_err(|e| anyhow::anyhow!("failed to reparse {:?}: {}", path, e))?;
+
+        assert!(reparsed.structurally_equal(&node), "round-trip changed {:?}", path);
+        assert_eq!(reparsed.to_xml_string(), serialized);
+    }
+
+    let original = parse_xml("<root><a>1</a></root>")?;
+    let changed = parse_xml("<root><a>2</a></root>")?;
+    assert!(!original.structurally_equal(&changed));
+    Ok(())
+}
+
 #[test]
 fn prints_visual_tree() -> Result<()> {
     let path = "tests/samples/2names.xml";
-    let node = XmlNode::from_path(&path)
+    let node = XmlNode::from_path(path)
         .map_err(|e| anyhow::anyhow!("failed to parse {:?}: {}", path, e))?;
 
     println!("\n====== Parsed XML Tree =====");
@@ -102,3 +192,82 @@ fn prints_visual_tree() -> Result<()> {
 }
 
 
+
+#[test]
+fn finds_nodes_mentioning_text() -> Result<()> {
+    let node = XmlNode::from_path("tests/samples/5names.xml")?;
+
+    let names = node.find_text("obj0707").iter().map(|n| n.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["folder", "filename", "path"]);
+    assert_eq!(node.find_text("Unspecified").len(), 5);
+    assert!(node.find_text("unspecified").is_empty());
+    assert_eq!(node.find_text_ci("unspecified").len(), 5);
+
+    let everything = XmlNode::from_path("tests/samples/everything.txt")?;
+    assert!(everything.children[0].contains_text("there"));
+    assert!(everything.children[0].contains_text_ci("HELLO"));
+    assert!(!everything.contains_text("Hello"));
+    // the CDATA section and the comment are not elements
+    assert!(everything.find_text("10").is_empty());
+    Ok(())
+}
+
+#[test]
+fn infers_schema_of_annotation_file() -> Result<()> {
+    let schema = XmlNode::from_path("tests/samples/5names.xml")?.infer_schema();
+
+    let annotation = schema.element("annotation").expect("root is sketched");
+    assert_eq!(annotation.occurrences, 1);
+    assert_eq!(annotation.children.len(), 7);
+    assert!(annotation.children.contains(&("object".to_string(), Multiplicity::Many)));
+    assert!(annotation.children.contains(&("folder".to_string(), Multiplicity::One)));
+
+    let object = schema.element("object").expect("objects are sketched");
+    assert_eq!(object.occurrences, 5);
+    assert!(object.children.iter().all(|(_, m)| *m == Multiplicity::One));
+    assert!(object.attributes.is_empty());
+
+    assert_eq!(schema.elements[0].name, "annotation");
+    assert!(schema.to_string().starts_with("<annotation>\n  1 folder\n"));
+    Ok(())
+}
+
+#[test]
+fn get_nodes_rev_is_exact_reverse_of_document_order() -> Result<()> {
+    let node = XmlNode::from_path("tests/samples/5names.xml")?;
+
+    let forward = node.get_nodes("xmin");
+    let values = forward.iter().map(|n| n.content.as_str()).collect::<Vec<_>>();
+    assert_eq!(values, vec!["107", "339", "290", "21", "23"]);
+
+    let mut reverse = node.get_nodes_rev("xmin");
+    assert_eq!(reverse.first().map(|n| n.content.as_str()), Some("23"));
+    reverse.reverse();
+    assert!(forward.iter().zip(&reverse).all(|(a, b)| std::ptr::eq(*a, *b)));
+    assert_eq!(forward.len(), reverse.len());
+
+    // a parent comes before its descendants of the same name
+    let nested = parse_xml("<a><a><a/></a><a/></a>")?;
+    let depths = nested.get_nodes("a").iter().map(|n| n.children.len()).collect::<Vec<_>>();
+    assert_eq!(depths, vec![2, 1, 0, 0]);
+    Ok(())
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn from_path_mmap_matches_from_path() -> Result<()> {
+    let mapped = XmlNode::from_path_mmap("tests/samples/5names.xml")?;
+    let read = XmlNode::from_path("tests/samples/5names.xml")?;
+    assert!(mapped.structurally_equal(&read));
+    assert_eq!(mapped.get_nodes("object").len(), 5);
+
+    assert!(matches!(
+        XmlNode::from_path_mmap("tests/samples/invalid.txt"),
+        Err(ParseError::InFile { .. })
+    ));
+    assert!(matches!(
+        XmlNode::from_path_mmap("tests/samples/missing.xml"),
+        Err(ParseError::IoError(_))
+    ));
+    Ok(())
+}